@@ -0,0 +1,147 @@
+//! Where the addresses in the routing table came from.
+//!
+//! Every address handed to Kademlia is recorded together with the sources
+//! vouching for it. When a source withdraws an address, such as mDNS when a
+//! peer leaves the LAN, it is only removed from the routing table once no
+//! other source still vouches for it.
+
+use libp2p::{Multiaddr, PeerId};
+use std::{
+    collections::{BTreeSet, HashMap},
+    fmt,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Source {
+    /// Discovered on the local network.
+    Mdns,
+    /// Reported by the peer itself through the identify protocol.
+    Identify,
+    /// Given with `--bootstrap`.
+    Bootstrap,
+    /// Given to `DIAL`.
+    Manual,
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Source::Mdns => "mdns",
+            Source::Identify => "identify",
+            Source::Bootstrap => "bootstrap",
+            Source::Manual => "manual",
+        })
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct AddressBook {
+    peers: HashMap<PeerId, HashMap<Multiaddr, BTreeSet<Source>>>,
+}
+
+impl AddressBook {
+    /// Records that `source` vouches for `addr` of `peer`.
+    pub fn add(&mut self, peer: PeerId, addr: Multiaddr, source: Source) {
+        self.peers
+            .entry(peer)
+            .or_default()
+            .entry(addr)
+            .or_default()
+            .insert(source);
+    }
+
+    /// Withdraws `source`'s claim on `addr` of `peer`. Returns whether
+    /// another source still vouches for it.
+    pub fn remove(&mut self, peer: &PeerId, addr: &Multiaddr, source: Source) -> bool {
+        let addrs = match self.peers.get_mut(peer) {
+            Some(addrs) => addrs,
+            None => return false,
+        };
+        let vouched = match addrs.get_mut(addr) {
+            Some(sources) => {
+                sources.remove(&source);
+                !sources.is_empty()
+            }
+            None => false,
+        };
+        if !vouched {
+            addrs.remove(addr);
+        }
+        if addrs.is_empty() {
+            self.peers.remove(peer);
+        }
+        vouched
+    }
+
+    /// The addresses of `peer` that `source` vouches for.
+    pub fn vouched_by(&self, peer: &PeerId, source: Source) -> Vec<Multiaddr> {
+        self.peers
+            .get(peer)
+            .into_iter()
+            .flatten()
+            .filter(|(_, sources)| sources.contains(&source))
+            .map(|(addr, _)| addr.clone())
+            .collect()
+    }
+
+    /// Every known address with its sources, by peer.
+    pub fn iter(&self) -> impl Iterator<Item = (&PeerId, &Multiaddr, &BTreeSet<Source>)> {
+        self.peers
+            .iter()
+            .flat_map(|(peer, addrs)| addrs.iter().map(move |(addr, s)| (peer, addr, s)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(port: u16) -> Multiaddr {
+        format!("/ip4/127.0.0.1/tcp/{}", port).parse().unwrap()
+    }
+
+    #[test]
+    fn keeps_addresses_another_source_vouches_for() {
+        let mut book = AddressBook::default();
+        let peer = PeerId::random();
+        book.add(peer, addr(1), Source::Mdns);
+        book.add(peer, addr(1), Source::Identify);
+
+        assert!(book.remove(&peer, &addr(1), Source::Mdns));
+        assert_eq!(book.vouched_by(&peer, Source::Mdns), Vec::<Multiaddr>::new());
+        assert_eq!(book.vouched_by(&peer, Source::Identify), vec![addr(1)]);
+
+        assert!(!book.remove(&peer, &addr(1), Source::Identify));
+        assert_eq!(book.iter().count(), 0);
+    }
+
+    #[test]
+    fn removing_unknown_addresses_vouches_for_nothing() {
+        let mut book = AddressBook::default();
+        let peer = PeerId::random();
+        assert!(!book.remove(&peer, &addr(1), Source::Mdns));
+
+        book.add(peer, addr(1), Source::Mdns);
+        assert!(!book.remove(&peer, &addr(2), Source::Mdns));
+        // A source that never vouched for the address does not withdraw it.
+        assert!(book.remove(&peer, &addr(1), Source::Manual));
+        assert_eq!(book.vouched_by(&peer, Source::Mdns), vec![addr(1)]);
+    }
+
+    #[test]
+    fn lists_addresses_by_source_and_peer() {
+        let mut book = AddressBook::default();
+        let (peer, other) = (PeerId::random(), PeerId::random());
+        book.add(peer, addr(1), Source::Identify);
+        book.add(peer, addr(2), Source::Identify);
+        book.add(peer, addr(3), Source::Bootstrap);
+        book.add(other, addr(4), Source::Identify);
+
+        let mut identified = book.vouched_by(&peer, Source::Identify);
+        identified.sort();
+        assert_eq!(identified, vec![addr(1), addr(2)]);
+        assert_eq!(book.vouched_by(&peer, Source::Bootstrap), vec![addr(3)]);
+        assert_eq!(book.vouched_by(&other, Source::Identify), vec![addr(4)]);
+        assert_eq!(book.iter().count(), 4);
+    }
+}
@@ -0,0 +1,1342 @@
+use crate::{
+    addresses::{AddressBook, Source},
+    chunk::{self, Download, Manifest},
+    crdt::Crdt,
+    display::DisplayMode,
+    envelope::{self, Envelope, Kind},
+    store::DiskStore,
+    tombstone,
+    validator::{self, Validators},
+    watch,
+};
+use libp2p::{
+    core::Endpoint,
+    dcutr,
+    gossipsub::{self, PublishError},
+    identify,
+    identity::Keypair,
+    kad::{
+        self, store::RecordStore, AddProviderOk, GetProvidersOk, GetRecordError, GetRecordOk,
+        InboundRequest, PeerRecord, PutRecordOk, QueryId, QueryResult, Quorum, Record,
+        RecordKey as Key, K_VALUE,
+    },
+    mdns, relay,
+    swarm::{
+        behaviour::toggle::Toggle, ConnectionDenied, ConnectionId, FromSwarm, NetworkBehaviour,
+        THandler, THandlerInEvent, THandlerOutEvent, ToSwarm,
+    },
+    Multiaddr, PeerId,
+};
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+    convert::Infallible,
+    num::NonZeroUsize,
+    task::{Context, Poll},
+    time::Instant,
+};
+
+/// What an outbound query was started for, when that changes how its result
+/// is reported.
+pub enum PendingQuery {
+    /// Reading the current version of a key before deleting it.
+    ReadForDelete { key: Key, quorum: Quorum },
+    Delete,
+    /// Pushing the winner of a GET back to peers that returned a stale record.
+    ReadRepair,
+    /// Reading the current value of a key before swapping it.
+    Cas(CompareAndSwap),
+    /// Storing a chunk of a large value.
+    PutChunk,
+    /// Fetching chunk `index` of the large value under `key`.
+    GetChunk { key: Key, index: usize },
+}
+
+/// The records a GET has collected so far.
+pub struct Lookup {
+    pub key: Key,
+    /// How many records to collect before the GET is answered.
+    pub quorum: NonZeroUsize,
+    /// Whether the record held locally is collected too.
+    pub local: bool,
+    pub records: Vec<PeerRecord>,
+}
+
+/// A `CAS` waiting for the current value of its key.
+pub struct CompareAndSwap {
+    pub key: Key,
+    /// SHA-256 of the value the key must hold, or `None` if it must not
+    /// hold one.
+    pub expected: Option<Vec<u8>>,
+    pub value: Vec<u8>,
+    pub expires: Option<Instant>,
+    pub quorum: Quorum,
+}
+
+/// The protocols the node speaks.
+#[derive(NetworkBehaviour)]
+pub struct Network {
+    pub kademlia: kad::Behaviour<DiskStore>,
+    pub mdns: mdns::tokio::Behaviour,
+    /// Carries change notifications for watched keys. See [`watch`].
+    pub gossipsub: gossipsub::Behaviour,
+    /// Learns peers' listen addresses, and reports the address they see us
+    /// at to the swarm as a candidate external address, which is confirmed
+    /// once several peers agree on it.
+    pub identify: identify::Behaviour,
+    /// Relays connections for peers behind NAT, with `--relay`.
+    pub relay: Toggle<relay::Behaviour>,
+    /// Reserves slots on relays for `/p2p-circuit` listen addresses, and
+    /// dials peers through them.
+    pub relay_client: relay::client::Behaviour,
+    /// Upgrades relayed connections to direct ones by hole punching.
+    pub dcutr: dcutr::Behaviour,
+}
+
+/// The node: its protocols, and the state their events are handled with.
+/// Events are handled as they come out of [`Network`], so none reach the
+/// swarm.
+pub struct MyBehaviour {
+    pub network: Network,
+
+    /// How keys and values are printed.
+    pub display: DisplayMode,
+
+    /// The node's identity, used to sign records.
+    pub keypair: Keypair,
+
+    pub pending: HashMap<QueryId, PendingQuery>,
+
+    /// Records collected by running GETs.
+    pub lookups: HashMap<QueryId, Lookup>,
+
+    /// The sources of the addresses in the routing table.
+    pub addresses: AddressBook,
+
+    /// Chunked values being fetched, by key.
+    pub downloads: HashMap<Key, Download>,
+
+    /// Validators deciding which records are accepted per key namespace.
+    pub validators: Validators,
+
+    /// Quorum for GET, PUT and DELETE when the command does not set one.
+    pub quorum: Quorum,
+
+    /// Keys whose changes are shown. See [`watch`].
+    pub watched: HashSet<Key>,
+
+    /// The address each connected peer last reported seeing us at.
+    pub observed: HashMap<PeerId, Multiaddr>,
+}
+
+impl MyBehaviour {
+    /// Adds an address of `peer` to the routing table on behalf of `source`.
+    pub fn add_address(&mut self, peer: PeerId, addr: Multiaddr, source: Source) {
+        self.addresses.add(peer, addr.clone(), source);
+        self.network.kademlia.add_address(&peer, addr);
+    }
+
+    /// Withdraws `source`'s claim on an address of `peer`, removing it from
+    /// the routing table unless another source still vouches for it.
+    pub fn remove_address(&mut self, peer: PeerId, addr: Multiaddr, source: Source) {
+        if !self.addresses.remove(&peer, &addr, source) {
+            self.network.kademlia.remove_address(&peer, &addr);
+        }
+    }
+
+    /// How many connected peers last reported seeing us at `addr`.
+    pub fn observers(&self, addr: &Multiaddr) -> usize {
+        self.observed.values().filter(|observed| *observed == addr).count()
+    }
+
+    /// Starts a GET of `key`, answered once `quorum` records are in. A record
+    /// held locally counts as one of them.
+    pub fn get_record(&mut self, key: Key, quorum: Quorum) -> QueryId {
+        self.lookup(key, quorum, true)
+    }
+
+    /// Starts a GET of `key` that only `quorum` records from other peers
+    /// answer, for when our own copy may be the stale one.
+    fn get_remote_record(&mut self, key: Key, quorum: Quorum) -> QueryId {
+        self.lookup(key, quorum, false)
+    }
+
+    fn lookup(&mut self, key: Key, quorum: Quorum, local: bool) -> QueryId {
+        let id = self.network.kademlia.get_record(key.clone());
+        let lookup = Lookup {
+            key,
+            quorum: quorum_size(quorum),
+            local,
+            records: Vec::new(),
+        };
+        self.lookups.insert(id, lookup);
+        id
+    }
+
+    /// The verified record held locally for `key`.
+    fn local_envelope(&mut self, key: &Key) -> Option<Envelope> {
+        let record = self.network.kademlia.store_mut().get(key)?;
+        envelope::open(&record).ok()
+    }
+
+    /// The version of the record held locally for `key`, if it is versioned.
+    pub fn local_seq(&mut self, key: &Key) -> Option<u64> {
+        self.local_envelope(key)?.seq
+    }
+
+    /// Signs `value` and stores it under `key`, locally and on the closest
+    /// peers. If the record held locally is versioned, the write must have a
+    /// higher `seq`.
+    pub fn put(
+        &mut self,
+        key: Key,
+        value: &[u8],
+        seq: Option<u64>,
+        expires: Option<Instant>,
+        quorum: Quorum,
+    ) {
+        // Unversioned writes count as version zero, so they cannot replace
+        // a versioned record either.
+        let replaced = self.local_envelope(&key);
+        if let Some(local) = replaced.as_ref().and_then(|local| local.seq) {
+            if seq.unwrap_or(0) <= local {
+                eprintln!(
+                    "Refusing to replace version {} of {} with {}, use seq=next",
+                    local,
+                    self.display.render(key.as_ref()),
+                    seq.map_or_else(|| "an unversioned record".to_string(), |seq| {
+                        format!("version {}", seq)
+                    })
+                );
+                return;
+            }
+        }
+        let sealed = if value.len() > chunk::CHUNK_SIZE {
+            self.put_chunks(value, expires, quorum).and_then(|manifest| {
+                let payload = manifest.encode();
+                envelope::seal(&self.keypair, key, Kind::Manifest, seq, &payload, expires)
+            })
+        } else {
+            envelope::seal(&self.keypair, key, Kind::Value, seq, value, expires)
+        };
+        match sealed {
+            Ok(record) => {
+                self.publish(record, quorum);
+                self.remove_chunks(replaced.as_ref());
+            }
+            Err(err) => eprintln!("Failed to store record: {}", err),
+        }
+    }
+
+    /// Stores `value` as content-addressed chunks, returning the manifest
+    /// that lists them.
+    fn put_chunks(
+        &mut self,
+        value: &[u8],
+        expires: Option<Instant>,
+        quorum: Quorum,
+    ) -> Result<Manifest, String> {
+        let (manifest, chunks) = Manifest::split(value)?;
+        for chunk in chunks {
+            let key = validator::content_key(chunk);
+            let record = envelope::seal(&self.keypair, key, Kind::Value, None, chunk, expires)?;
+            // Chunks are only reachable through their manifest, so watchers
+            // hear about the manifest instead.
+            let id = self.store_record(record, quorum).ok_or("failed to store chunk")?;
+            self.pending.insert(id, PendingQuery::PutChunk);
+        }
+        Ok(manifest)
+    }
+
+    /// Removes the chunks of a `replaced` manifest from the local store, so
+    /// they are no longer republished. Chunks that a manifest still held
+    /// locally lists, such as the one replacing it, are kept.
+    fn remove_chunks(&mut self, replaced: Option<&Envelope>) {
+        let manifest = match replaced {
+            Some(envelope) if envelope.kind == Kind::Manifest => {
+                match Manifest::decode(&envelope.payload) {
+                    Ok(manifest) => manifest,
+                    Err(_) => return,
+                }
+            }
+            _ => return,
+        };
+        let store = self.network.kademlia.store_mut();
+        let listed: HashSet<Key> = store
+            .records()
+            .filter_map(|record| envelope::open(&record).ok())
+            .filter(|envelope| envelope.kind == Kind::Manifest)
+            .filter_map(|envelope| Manifest::decode(&envelope.payload).ok())
+            .flat_map(|manifest| manifest.chunk_keys().collect::<Vec<_>>())
+            .collect();
+        for key in manifest.chunk_keys() {
+            if !listed.contains(&key) {
+                store.remove(&key);
+            }
+        }
+    }
+
+    /// Applies `update` to the CRDT held locally for `key`, or to `None` if
+    /// there is none yet, and publishes the result.
+    pub fn update_crdt(
+        &mut self,
+        key: Key,
+        quorum: Quorum,
+        update: impl FnOnce(Option<Crdt>) -> Result<Crdt, String>,
+    ) {
+        let (current, seq) = match self.local_envelope(&key) {
+            None => (None, None),
+            Some(local) => match local.kind {
+                Kind::Crdt => match Crdt::decode(&local.payload) {
+                    Ok(crdt) => (Some(crdt), local.seq),
+                    Err(err) => {
+                        eprintln!("Invalid CRDT in {}: {}", self.display.render(key.as_ref()), err);
+                        return;
+                    }
+                },
+                // Start a new generation, which replicas of the deleted CRDT
+                // are not merged into.
+                Kind::Tombstone => (None, Some(local.seq.unwrap_or(0) + 1)),
+                Kind::Value | Kind::Manifest => {
+                    eprintln!(
+                        "{} holds a plain value, not a CRDT",
+                        self.display.render(key.as_ref())
+                    );
+                    return;
+                }
+            },
+        };
+        let crdt = match update(current) {
+            Ok(crdt) => crdt,
+            Err(err) => {
+                eprintln!("{}", err);
+                return;
+            }
+        };
+        match self.seal_crdt(key, &crdt, seq, None) {
+            Ok(record) => {
+                self.publish(record, quorum);
+            }
+            Err(err) => eprintln!("Failed to sign record: {}", err),
+        }
+    }
+
+    /// Signs `crdt` as our own replica of `key`. CRDT records carry no signed
+    /// expiry, since merging would have to pick one; `expires` only applies
+    /// to the stored copy.
+    fn seal_crdt(
+        &self,
+        key: Key,
+        crdt: &Crdt,
+        seq: Option<u64>,
+        expires: Option<Instant>,
+    ) -> Result<Record, String> {
+        let mut record = envelope::seal(&self.keypair, key, Kind::Crdt, seq, &crdt.encode(), None)?;
+        record.expires = expires;
+        Ok(record)
+    }
+
+    /// Stores a record we signed like [`Self::store_record`], and tells
+    /// watchers about it once it is stored.
+    fn publish(&mut self, record: Record, quorum: Quorum) -> Option<QueryId> {
+        let id = self.store_record(record.clone(), quorum)?;
+        self.notify(&record);
+        Some(id)
+    }
+
+    /// Validates a record we signed and stores it locally and on the closest
+    /// peers.
+    fn store_record(&mut self, record: Record, quorum: Quorum) -> Option<QueryId> {
+        if let Err(err) = self.validate_own(&record) {
+            eprintln!("Invalid record: {}", err);
+            return None;
+        }
+        match self.network.kademlia.put_record(record, quorum) {
+            Ok(id) => Some(id),
+            Err(err) => {
+                eprintln!("Failed to store record locally: {:?}", err);
+                None
+            }
+        }
+    }
+
+    /// Checks a record we signed against the validator for its key.
+    fn validate_own(&self, record: &Record) -> Result<(), String> {
+        let envelope = envelope::open(record).map_err(|e| e.to_string())?;
+        self.validators.validate(&record.key, &envelope)
+    }
+
+    /// Tells peers watching the record's key about the change.
+    fn notify(&mut self, record: &Record) {
+        let change = watch::encode(&record.key, &record.value);
+        match self.network.gossipsub.publish(watch::topic(), change) {
+            // No other node is around to hear about it.
+            Ok(_) | Err(PublishError::InsufficientPeers) => {}
+            Err(err) => eprintln!(
+                "Failed to notify watchers of {}: {:?}",
+                self.display.render(record.key.as_ref()),
+                err
+            ),
+        }
+    }
+
+    /// Deletes `key` once its current version, as held by the closest peers,
+    /// is known, see [`Self::finish_delete`].
+    pub fn delete(&mut self, key: Key, quorum: Quorum) {
+        // Our own copy is left out, since it may be the stale one; the
+        // tombstone still outranks it, see `finish_delete`.
+        let id = self.get_remote_record(key.clone(), quorum);
+        self.pending.insert(id, PendingQuery::ReadForDelete { key, quorum });
+    }
+
+    /// Removes `key` locally and publishes a tombstone so replicas stop
+    /// serving the value. The tombstone needs a higher `seq` than both
+    /// `current` and the record held locally, or replicas of a versioned
+    /// record would keep it.
+    fn finish_delete(&mut self, key: Key, quorum: Quorum, current: Option<&Envelope>) {
+        let next = |envelope: &Envelope| match envelope.kind {
+            // Replicas of a CRDT are merged rather than replaced, so only a
+            // newer generation deletes it, even if it is unversioned.
+            Kind::Crdt => Some(envelope.seq.unwrap_or(0) + 1),
+            _ => envelope.seq.map(|seq| seq + 1),
+        };
+        let local = self.local_envelope(&key);
+        let seq = current.and_then(next).max(local.as_ref().and_then(next));
+        // `None` as soon as one version never expires.
+        let deleted_expires = current
+            .into_iter()
+            .chain(&local)
+            .map(Envelope::expires)
+            .try_fold(Instant::now(), |latest, expires| Some(latest.max(expires?)));
+        let record = match tombstone::create(&self.keypair, key, seq, deleted_expires) {
+            Ok(record) => record,
+            Err(err) => {
+                eprintln!("Failed to sign tombstone: {}", err);
+                return;
+            }
+        };
+        if let Err(err) = self.validate_own(&record) {
+            eprintln!("Cannot delete {}: {}", self.display.render(record.key.as_ref()), err);
+            return;
+        }
+        self.network.kademlia.remove_record(&record.key);
+        match self.network.kademlia.put_record(record.clone(), quorum) {
+            Ok(id) => {
+                self.pending.insert(id, PendingQuery::Delete);
+                self.notify(&record);
+                self.remove_chunks(local.as_ref());
+            }
+            Err(err) => eprintln!("Failed to store tombstone locally: {:?}", err),
+        }
+    }
+
+    /// Deletes `key` when fewer peers than the quorum returned it, `records`,
+    /// possibly none because it is only held locally.
+    fn delete_unreplicated(&mut self, key: Key, quorum: Quorum, records: Vec<PeerRecord>) {
+        let current = match self.resolve_records(records) {
+            Some(current) => Some(current),
+            None => self.local_envelope(&key),
+        };
+        match current {
+            Some(current) if current.kind == Kind::Tombstone => {
+                println!("{} is already deleted", self.display.render(key.as_ref()))
+            }
+            Some(current) => self.finish_delete(key, quorum, Some(&current)),
+            None => eprintln!(
+                "Cannot delete {}: no record found",
+                self.display.render(key.as_ref())
+            ),
+        }
+    }
+
+    /// Replaces the value of `key` with `value` if its current value, as
+    /// held by the closest peers, hashes to `expected`.
+    ///
+    /// The record held locally is not consulted, as a writer that is not
+    /// one of the closest peers still holds its own last write. The new
+    /// record gets a higher `seq` than the current one, so replicas prefer
+    /// it. Two writers racing on the same value still both succeed locally;
+    /// the GET after the race settles on one of them.
+    pub fn compare_and_swap(
+        &mut self,
+        key: Key,
+        expected: Option<Vec<u8>>,
+        value: Vec<u8>,
+        expires: Option<Instant>,
+        quorum: Quorum,
+    ) {
+        let id = self.get_remote_record(key.clone(), quorum);
+        let cas = CompareAndSwap {
+            key,
+            expected,
+            value,
+            expires,
+            quorum,
+        };
+        self.pending.insert(id, PendingQuery::Cas(cas));
+    }
+
+    /// Completes `cas` once the current value of its key is known. A
+    /// `current` of `None` means the key holds no value.
+    fn finish_cas(&mut self, cas: CompareAndSwap, current: Option<&Envelope>) {
+        let hash = current.and_then(Envelope::value_hash);
+        if hash != cas.expected {
+            eprintln!(
+                "Compare-and-swap on {} failed: the value has changed, its hash is now {}",
+                self.display.render(cas.key.as_ref()),
+                hash.map_or_else(|| "none".to_string(), hex::encode)
+            );
+            return;
+        }
+        let seq = current
+            .and_then(|current| current.seq)
+            .max(self.local_seq(&cas.key))
+            .unwrap_or(0)
+            + 1;
+        self.put(cas.key, &cas.value, Some(seq), cas.expires, cas.quorum);
+    }
+
+    /// Decides whether a record pushed to us by a peer goes into the store.
+    fn accept_record(&mut self, mut record: Record) {
+        let envelope = match envelope::open(&record) {
+            Ok(envelope) => envelope,
+            Err(err) => {
+                eprintln!(
+                    "Rejected record for {}: {}",
+                    self.display.render(record.key.as_ref()),
+                    err
+                );
+                return;
+            }
+        };
+        if let Err(err) = self.validators.validate(&record.key, &envelope) {
+            eprintln!(
+                "Rejected record for {}: {}",
+                self.display.render(record.key.as_ref()),
+                err
+            );
+            return;
+        }
+        // Keep what we hold unless the incoming record beats it. This is also
+        // what stops a stale replica from overwriting a newer tombstone.
+        // Replicas of the same CRDT are merged instead.
+        let existing = self.network.kademlia.store_mut().get(&record.key).map(Cow::into_owned);
+        if let Some(existing) = existing {
+            if let Ok(current) = envelope::open(&existing) {
+                if same_crdt(&current, &envelope) {
+                    let merged = decode_crdt(&current).and_then(|mut merged| {
+                        merged.merge(&decode_crdt(&envelope)?)?;
+                        Ok(merged)
+                    });
+                    match merged {
+                        Err(err) => {
+                            eprintln!(
+                                "Rejected record for {}: {}",
+                                self.display.render(record.key.as_ref()),
+                                err
+                            );
+                            return;
+                        }
+                        Ok(merged) if decode_crdt(&current).as_ref() == Ok(&merged) => return,
+                        // The inbound replica already has everything we have.
+                        Ok(merged) if decode_crdt(&envelope).as_ref() == Ok(&merged) => {}
+                        Ok(merged) => {
+                            let expires = later(existing.expires, record.expires);
+                            let merged = self.seal_crdt(record.key, &merged, current.seq, expires);
+                            match merged.map(|merged| self.network.kademlia.store_mut().put(merged)) {
+                                Ok(Ok(())) => {}
+                                Ok(Err(err)) => eprintln!("Failed to store record: {:?}", err),
+                                Err(err) => eprintln!("Failed to sign record: {}", err),
+                            }
+                            return;
+                        }
+                    }
+                } else if self.validators.select(&record.key, &[&current, &envelope]) == 0 {
+                    return;
+                }
+            }
+        }
+
+        // Never keep a record past the expiry its publisher signed.
+        record.expires = match (record.expires, envelope.expires()) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+
+        if let Err(err) = self.network.kademlia.store_mut().put(record) {
+            eprintln!("Failed to store record: {:?}", err);
+        }
+    }
+
+    /// Picks one winner among the records peers returned for a GET and
+    /// pushes it back to the peers that returned something else.
+    fn resolve_records(&mut self, records: Vec<PeerRecord>) -> Option<Envelope> {
+        let key = records.first()?.record.key.clone();
+        let now = Instant::now();
+
+        let mut candidates = Vec::new();
+        for PeerRecord { peer, record } in records {
+            if record.is_expired(now) {
+                continue;
+            }
+            let verified = envelope::open(&record)
+                .map_err(|e| e.to_string())
+                .and_then(|envelope| {
+                    self.validators.validate(&key, &envelope)?;
+                    Ok(envelope)
+                });
+            match verified {
+                // The expiry the record came with is up to the peer sending
+                // it; the signed one is not.
+                Ok(envelope) if envelope.is_expired() => {}
+                Ok(envelope) => candidates.push((peer, record, envelope)),
+                Err(err) => eprintln!(
+                    "Ignoring unverified record {} from {:?}: {}",
+                    self.display.render(key.as_ref()),
+                    peer,
+                    err
+                ),
+            }
+        }
+        if candidates.is_empty() {
+            eprintln!("No valid record for {}", self.display.render(key.as_ref()));
+            return None;
+        }
+
+        let envelopes: Vec<&Envelope> = candidates.iter().map(|(_, _, e)| e).collect();
+        let winner = self.validators.select(&key, &envelopes);
+        let (_, record, envelope) = &candidates[winner];
+        let (record, envelope) = match envelope.kind {
+            Kind::Crdt => self.merge_replicas(&key, &candidates, winner)?,
+            _ => (record.clone(), envelope.clone()),
+        };
+
+        let merged = decode_crdt(&envelope).ok();
+        let stale: Vec<Option<PeerId>> = candidates
+            .iter()
+            .filter(|(_, r, e)| {
+                r.value != record.value
+                    && !(same_crdt(e, &envelope) && decode_crdt(e).ok() == merged)
+            })
+            .map(|(peer, _, _)| *peer)
+            .collect();
+        if stale.is_empty() {
+            return Some(envelope);
+        }
+        println!(
+            "Peers disagreed on {}: {} of {} records were stale",
+            self.display.render(key.as_ref()),
+            stale.len(),
+            candidates.len()
+        );
+
+        // Read repair. A `None` peer is our own store.
+        let peers: Vec<PeerId> = stale.iter().flatten().copied().collect();
+        if stale.contains(&None) {
+            if let Err(err) = self.network.kademlia.store_mut().put(record.clone()) {
+                eprintln!("Failed to repair local record: {:?}", err);
+            }
+        }
+        if !peers.is_empty() {
+            let id = self
+                .network
+                .kademlia
+                .put_record_to(record, peers.into_iter(), Quorum::All);
+            self.pending.insert(id, PendingQuery::ReadRepair);
+        }
+        Some(envelope)
+    }
+
+    /// Merges the replicas of the CRDT generation that won a GET. A replica
+    /// that already holds the merged state is reused, otherwise the merged
+    /// state is signed as our own replica.
+    fn merge_replicas(
+        &self,
+        key: &Key,
+        candidates: &[(Option<PeerId>, Record, Envelope)],
+        winner: usize,
+    ) -> Option<(Record, Envelope)> {
+        let generation = &candidates[winner].2;
+        let mut merged: Option<Crdt> = None;
+        let mut expires = None;
+        let mut replicas = Vec::new();
+        for (peer, record, envelope) in candidates {
+            if !same_crdt(envelope, generation) {
+                continue;
+            }
+            let replica = decode_crdt(envelope).and_then(|replica| {
+                match &mut merged {
+                    Some(merged) => merged.merge(&replica)?,
+                    None => merged = Some(replica.clone()),
+                }
+                Ok(replica)
+            });
+            match replica {
+                Ok(replica) => {
+                    expires = Some(expires.map_or(record.expires, |e| later(e, record.expires)));
+                    replicas.push((record, replica));
+                }
+                Err(err) => eprintln!(
+                    "Ignoring invalid replica of {} from {:?}: {}",
+                    self.display.render(key.as_ref()),
+                    peer,
+                    err
+                ),
+            }
+        }
+        let merged = merged?;
+
+        let record = match replicas.into_iter().find(|(_, replica)| *replica == merged) {
+            Some((record, _)) => record.clone(),
+            None => match self.seal_crdt(key.clone(), &merged, generation.seq, expires.flatten()) {
+                Ok(record) => record,
+                Err(err) => {
+                    eprintln!("Failed to sign merged record: {}", err);
+                    return None;
+                }
+            },
+        };
+        let envelope = envelope::open(&record).ok()?;
+        Some((record, envelope))
+    }
+
+    /// Starts fetching the chunks listed by the manifest `envelope` for a GET
+    /// of `key`.
+    fn fetch_chunks(&mut self, key: Key, envelope: Envelope) {
+        let manifest = match Manifest::decode(&envelope.payload) {
+            Ok(manifest) => manifest,
+            Err(err) => {
+                eprintln!("Invalid manifest for {}: {}", self.display.render(key.as_ref()), err);
+                return;
+            }
+        };
+        for (index, chunk) in manifest.chunk_keys().enumerate() {
+            let id = self.get_record(chunk, Quorum::One);
+            let pending = PendingQuery::GetChunk {
+                key: key.clone(),
+                index,
+            };
+            self.pending.insert(id, pending);
+        }
+        // Replaces any earlier download of the same key.
+        self.downloads.insert(key, Download::new(envelope, manifest));
+    }
+
+    /// Adds chunk `index` to the download of `key`, reporting the value once
+    /// it is complete.
+    fn chunk_fetched(&mut self, key: Key, index: usize, chunk: Key, winner: Option<Envelope>) {
+        let download = match self.downloads.get_mut(&key) {
+            Some(download) => download,
+            None => return,
+        };
+        // The result of an earlier, replaced download.
+        if download.manifest.chunk_keys().nth(index) != Some(chunk) {
+            return;
+        }
+        match winner {
+            Some(winner) => download.chunks[index] = Some(winner.payload),
+            None => {
+                self.downloads.remove(&key);
+                eprintln!(
+                    "Failed to get chunk {} of {}",
+                    index,
+                    self.display.render(key.as_ref())
+                );
+                return;
+            }
+        }
+        let value = match download.value() {
+            Some(value) => value,
+            None => return,
+        };
+        let download = self.downloads.remove(&key).expect("download exists");
+        if let Err(err) = download.manifest.verify(&value) {
+            eprintln!(
+                "Chunks of {} do not match their manifest: {}",
+                self.display.render(key.as_ref()),
+                err
+            );
+            return;
+        }
+        let envelope = Envelope {
+            kind: Kind::Value,
+            payload: value,
+            ..download.envelope
+        };
+        self.print_record(&key, &envelope);
+    }
+
+    /// Reports the winner of a GET.
+    fn print_record(&self, key: &Key, envelope: &Envelope) {
+        match envelope.kind {
+            Kind::Tombstone => println!(
+                "Record {} was deleted by {:?} {}s ago",
+                self.display.render(key.as_ref()),
+                envelope.publisher,
+                envelope.age().as_secs()
+            ),
+            Kind::Value => println!(
+                "Got record {} {} ({}verified, published by {:?}, sha256 {})",
+                self.display.render(key.as_ref()),
+                self.display.render(&envelope.payload),
+                envelope
+                    .seq
+                    .map_or_else(String::new, |seq| format!("version {}, ", seq)),
+                envelope.publisher,
+                hex::encode(envelope.value_hash().unwrap_or_default())
+            ),
+            Kind::Manifest => println!(
+                "Got manifest of {} from {:?}",
+                self.display.render(key.as_ref()),
+                envelope.publisher
+            ),
+            Kind::Crdt => match Crdt::decode(&envelope.payload) {
+                Ok(crdt) => println!(
+                    "Got {} {} {}",
+                    crdt.type_name(),
+                    self.display.render(key.as_ref()),
+                    self.render_crdt(&crdt)
+                ),
+                Err(err) => eprintln!(
+                    "Invalid CRDT in {}: {}",
+                    self.display.render(key.as_ref()),
+                    err
+                ),
+            },
+        }
+    }
+    /// Reports a change to a watched key.
+    fn print_change(&self, key: &Key, envelope: &Envelope) {
+        match envelope.kind {
+            Kind::Value => println!(
+                "Key {} changed to {} by {:?}",
+                self.display.render(key.as_ref()),
+                self.display.render(&envelope.payload),
+                envelope.publisher
+            ),
+            Kind::Tombstone => println!(
+                "Key {} was deleted by {:?}",
+                self.display.render(key.as_ref()),
+                envelope.publisher
+            ),
+            Kind::Manifest => match Manifest::decode(&envelope.payload) {
+                Ok(manifest) => println!(
+                    "Key {} changed to a value of {} bytes by {:?}",
+                    self.display.render(key.as_ref()),
+                    manifest.len,
+                    envelope.publisher
+                ),
+                Err(err) => eprintln!(
+                    "Invalid manifest for {}: {}",
+                    self.display.render(key.as_ref()),
+                    err
+                ),
+            },
+            Kind::Crdt => match Crdt::decode(&envelope.payload) {
+                Ok(crdt) => println!(
+                    "Key {} changed to {} {} by {:?}",
+                    self.display.render(key.as_ref()),
+                    crdt.type_name(),
+                    self.render_crdt(&crdt),
+                    envelope.publisher
+                ),
+                Err(err) => eprintln!(
+                    "Invalid CRDT in {}: {}",
+                    self.display.render(key.as_ref()),
+                    err
+                ),
+            },
+        }
+    }
+
+    fn render_crdt(&self, crdt: &Crdt) -> String {
+        match crdt {
+            Crdt::GCounter(counter) => counter.value().to_string(),
+            Crdt::PnCounter(counter) => counter.value().to_string(),
+            Crdt::LwwRegister(register) => self.display.render(register.value()),
+            Crdt::OrSet(set) => {
+                let members: Vec<String> = set.members().map(|m| self.display.render(m)).collect();
+                format!("{{{}}}", members.join(", "))
+            }
+        }
+    }
+}
+
+/// Whether two records are replicas of the same CRDT generation, which are
+/// merged rather than resolved to a winner.
+fn same_crdt(a: &Envelope, b: &Envelope) -> bool {
+    a.kind == Kind::Crdt && b.kind == Kind::Crdt && a.seq == b.seq
+}
+
+fn decode_crdt(envelope: &Envelope) -> Result<Crdt, String> {
+    Crdt::decode(&envelope.payload).map_err(|e| e.to_string())
+}
+
+/// The number of records `quorum` asks for, out of the replication factor.
+fn quorum_size(quorum: Quorum) -> NonZeroUsize {
+    match quorum {
+        Quorum::One => NonZeroUsize::MIN,
+        Quorum::Majority => NonZeroUsize::new(K_VALUE.get() / 2 + 1).expect("K_VALUE > 0"),
+        Quorum::All => K_VALUE,
+        Quorum::N(n) => n.min(K_VALUE),
+    }
+}
+
+/// The later of two expiries, where `None` never expires.
+fn later(a: Option<Instant>, b: Option<Instant>) -> Option<Instant> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.max(b)),
+        _ => None,
+    }
+}
+
+impl NetworkBehaviour for MyBehaviour {
+    type ConnectionHandler = THandler<Network>;
+    type ToSwarm = Infallible;
+
+    fn handle_pending_inbound_connection(
+        &mut self,
+        connection_id: ConnectionId,
+        local_addr: &Multiaddr,
+        remote_addr: &Multiaddr,
+    ) -> Result<(), ConnectionDenied> {
+        self.network
+            .handle_pending_inbound_connection(connection_id, local_addr, remote_addr)
+    }
+
+    fn handle_established_inbound_connection(
+        &mut self,
+        connection_id: ConnectionId,
+        peer: PeerId,
+        local_addr: &Multiaddr,
+        remote_addr: &Multiaddr,
+    ) -> Result<THandler<Self>, ConnectionDenied> {
+        self.network.handle_established_inbound_connection(
+            connection_id,
+            peer,
+            local_addr,
+            remote_addr,
+        )
+    }
+
+    fn handle_pending_outbound_connection(
+        &mut self,
+        connection_id: ConnectionId,
+        maybe_peer: Option<PeerId>,
+        addresses: &[Multiaddr],
+        effective_role: Endpoint,
+    ) -> Result<Vec<Multiaddr>, ConnectionDenied> {
+        self.network.handle_pending_outbound_connection(
+            connection_id,
+            maybe_peer,
+            addresses,
+            effective_role,
+        )
+    }
+
+    fn handle_established_outbound_connection(
+        &mut self,
+        connection_id: ConnectionId,
+        peer: PeerId,
+        addr: &Multiaddr,
+        role_override: Endpoint,
+    ) -> Result<THandler<Self>, ConnectionDenied> {
+        self.network.handle_established_outbound_connection(
+            connection_id,
+            peer,
+            addr,
+            role_override,
+        )
+    }
+
+    fn on_swarm_event(&mut self, event: FromSwarm) {
+        if let FromSwarm::ConnectionClosed(closed) = event {
+            if closed.remaining_established == 0 {
+                self.observed.remove(&closed.peer_id);
+            }
+        }
+        self.network.on_swarm_event(event)
+    }
+
+    fn on_connection_handler_event(
+        &mut self,
+        peer_id: PeerId,
+        connection_id: ConnectionId,
+        event: THandlerOutEvent<Self>,
+    ) {
+        self.network
+            .on_connection_handler_event(peer_id, connection_id, event)
+    }
+
+    fn poll(&mut self, cx: &mut Context<'_>) -> Poll<ToSwarm<Infallible, THandlerInEvent<Self>>> {
+        // Handling an event may queue more work in the network, so keep
+        // polling until it has nothing left for the swarm.
+        loop {
+            match self.network.poll(cx) {
+                Poll::Ready(ToSwarm::GenerateEvent(event)) => self.on_network_event(event),
+                Poll::Ready(action) => {
+                    return Poll::Ready(action.map_out(|_| unreachable!("handled above")))
+                }
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+impl MyBehaviour {
+    fn on_network_event(&mut self, event: NetworkEvent) {
+        match event {
+            NetworkEvent::Kademlia(event) => self.on_kademlia_event(event),
+            NetworkEvent::Mdns(event) => self.on_mdns_event(event),
+            NetworkEvent::Gossipsub(event) => self.on_gossipsub_event(event),
+            NetworkEvent::Identify(event) => self.on_identify_event(event),
+            NetworkEvent::Relay(event) => self.on_relay_event(event),
+            NetworkEvent::RelayClient(event) => self.on_relay_client_event(event),
+            NetworkEvent::Dcutr(event) => self.on_dcutr_event(event),
+        }
+    }
+
+    fn on_mdns_event(&mut self, event: mdns::Event) {
+        match event {
+            mdns::Event::Discovered(list) => {
+                for (peer_id, multiaddrr) in list {
+                    self.add_address(peer_id, multiaddrr, Source::Mdns);
+                }
+            }
+            // The peer left the LAN, or stopped answering.
+            mdns::Event::Expired(list) => {
+                for (peer_id, multiaddrr) in list {
+                    self.remove_address(peer_id, multiaddrr, Source::Mdns);
+                }
+            }
+        }
+    }
+
+    fn on_identify_event(&mut self, event: identify::Event) {
+        if let identify::Event::Received { peer_id, info, .. } = event {
+            self.observed.insert(peer_id, info.observed_addr.clone());
+            // Only peers speaking Kademlia belong in the routing table.
+            if !info.protocols.contains(&kad::PROTOCOL_NAME) {
+                return;
+            }
+            // The listen addresses replace whatever the peer reported before.
+            for addr in self.addresses.vouched_by(&peer_id, Source::Identify) {
+                if !info.listen_addrs.contains(&addr) {
+                    self.remove_address(peer_id, addr, Source::Identify);
+                }
+            }
+            for addr in info.listen_addrs {
+                self.add_address(peer_id, addr, Source::Identify);
+            }
+        }
+    }
+
+    fn on_relay_event(&mut self, event: relay::Event) {
+        match event {
+            relay::Event::ReservationReqAccepted {
+                src_peer_id,
+                renewed: false,
+            } => {
+                println!("Relaying for {:?}", src_peer_id);
+            }
+            relay::Event::ReservationTimedOut { src_peer_id } => {
+                println!("Stopped relaying for {:?}", src_peer_id);
+            }
+            relay::Event::CircuitReqAccepted {
+                src_peer_id,
+                dst_peer_id,
+            } => {
+                println!("Relaying from {:?} to {:?}", src_peer_id, dst_peer_id);
+            }
+            _ => {}
+        }
+    }
+
+    /// Failed reservations and circuits surface as listener and dial errors
+    /// in the swarm instead.
+    fn on_relay_client_event(&mut self, event: relay::client::Event) {
+        if let relay::client::Event::ReservationReqAccepted {
+            relay_peer_id,
+            renewal: false,
+            ..
+        } = event
+        {
+            println!("Reachable through relay {:?}", relay_peer_id);
+        }
+    }
+
+    fn on_dcutr_event(&mut self, event: dcutr::Event) {
+        match event.result {
+            Ok(_) => {
+                println!(
+                    "Upgraded relayed connection to {:?} to a direct one",
+                    event.remote_peer_id
+                );
+            }
+            Err(error) => {
+                eprintln!(
+                    "Failed to upgrade relayed connection to {:?}: {}",
+                    event.remote_peer_id, error
+                );
+            }
+        }
+    }
+
+    fn on_gossipsub_event(&mut self, event: gossipsub::Event) {
+        if let gossipsub::Event::Message { message, .. } = event {
+            if message.topic != watch::topic().hash() {
+                return;
+            }
+            let (key, value) = match watch::decode(&message.data) {
+                Ok(change) => change,
+                Err(err) => {
+                    eprintln!("Ignoring malformed change from {:?}: {}", message.source, err);
+                    return;
+                }
+            };
+            if !self.watched.contains(&key) {
+                return;
+            }
+            let record = Record {
+                key: key.clone(),
+                value,
+                publisher: message.source,
+                expires: None,
+            };
+            let verified = envelope::open(&record)
+                .map_err(|e| e.to_string())
+                .and_then(|envelope| {
+                    self.validators.validate(&key, &envelope)?;
+                    Ok(envelope)
+                });
+            match verified {
+                Ok(envelope) => self.print_change(&key, &envelope),
+                Err(err) => eprintln!(
+                    "Ignoring unverified change to {} from {:?}: {}",
+                    self.display.render(key.as_ref()),
+                    message.source,
+                    err
+                ),
+            }
+        }
+    }
+
+    fn on_kademlia_event(&mut self, message: kad::Event) {
+        match message {
+            kad::Event::OutboundQueryProgressed { id, result, .. } => match result {
+                QueryResult::GetProviders(Ok(GetProvidersOk::FoundProviders { key, providers })) => {
+                    for peer in providers {
+                        println!(
+                            "Peer {:?} provides key {}",
+                            peer,
+                            self.display.render(key.as_ref())
+                        );
+                    }
+                }
+                QueryResult::GetProviders(Err(err)) => {
+                    eprintln!("Failed to get providers: {:?}", err);
+                }
+                QueryResult::GetRecord(Ok(GetRecordOk::FoundRecord(record))) => {
+                    self.record_found(id, record)
+                }
+                QueryResult::GetRecord(Ok(GetRecordOk::FinishedWithNoAdditionalRecord {
+                    ..
+                })) => self.lookup_finished(id, None),
+                QueryResult::GetRecord(Err(err)) => self.lookup_finished(id, Some(err)),
+                QueryResult::PutRecord(Ok(PutRecordOk { key })) => match self.pending.remove(&id) {
+                    Some(PendingQuery::Delete) => {
+                        println!(
+                            "Successfully deleted record {}",
+                            self.display.render(key.as_ref())
+                        );
+                    }
+                    Some(PendingQuery::ReadRepair) => {
+                        println!(
+                            "Repaired stale replicas of {}",
+                            self.display.render(key.as_ref())
+                        );
+                    }
+                    Some(PendingQuery::PutChunk) => {}
+                    Some(
+                        PendingQuery::ReadForDelete { .. }
+                        | PendingQuery::Cas(_)
+                        | PendingQuery::GetChunk { .. },
+                    )
+                    | None => {
+                        println!(
+                            "Successfully put record {}",
+                            self.display.render(key.as_ref())
+                        );
+                    }
+                },
+                QueryResult::PutRecord(Err(err)) => match self.pending.remove(&id) {
+                    Some(PendingQuery::Delete) => {
+                        eprintln!("Failed to propagate tombstone: {:?}", err);
+                    }
+                    Some(PendingQuery::ReadRepair) => {
+                        eprintln!("Failed to repair stale replicas: {:?}", err);
+                    }
+                    Some(PendingQuery::PutChunk) => {
+                        eprintln!("Failed to store chunk: {:?}", err);
+                    }
+                    Some(
+                        PendingQuery::ReadForDelete { .. }
+                        | PendingQuery::Cas(_)
+                        | PendingQuery::GetChunk { .. },
+                    )
+                    | None => {
+                        eprintln!("Failed to put record: {:?}", err);
+                    }
+                },
+                QueryResult::StartProviding(Ok(AddProviderOk { key })) => {
+                    println!(
+                        "Successfully put provider record {}",
+                        self.display.render(key.as_ref())
+                    );
+                }
+                QueryResult::StartProviding(Err(err)) => {
+                    eprintln!("Failed to put provider record: {:?}", err);
+                }
+                QueryResult::Bootstrap(Err(err)) => {
+                    eprintln!("Failed to bootstrap: {:?}", err);
+                }
+                _ => {}
+            },
+            kad::Event::InboundRequest { request } => match request {
+                InboundRequest::PutRecord {
+                    record: Some(record),
+                    ..
+                } => self.accept_record(record),
+                InboundRequest::AddProvider {
+                    record: Some(record),
+                } => {
+                    if let Err(err) = self.network.kademlia.store_mut().add_provider(record) {
+                        eprintln!("Failed to store provider record: {:?}", err);
+                    }
+                }
+                _ => {}
+            },
+            _ => {}
+        }
+    }
+
+    /// Adds a record a GET found, finishing the GET once it has enough.
+    fn record_found(&mut self, id: QueryId, record: PeerRecord) {
+        let lookup = match self.lookups.get_mut(&id) {
+            Some(lookup) => lookup,
+            None => return,
+        };
+        if record.peer.is_none() && !lookup.local {
+            return;
+        }
+        lookup.records.push(record);
+        if lookup.records.len() >= lookup.quorum.get() {
+            // Reported as finished, with what was collected, right away.
+            if let Some(mut query) = self.network.kademlia.query_mut(&id) {
+                query.finish();
+            }
+        }
+    }
+
+    /// Answers a GET once its query is over, with `err` if it failed.
+    fn lookup_finished(&mut self, id: QueryId, err: Option<GetRecordError>) {
+        let lookup = match self.lookups.remove(&id) {
+            Some(lookup) => lookup,
+            None => return,
+        };
+        let result = match err {
+            _ if lookup.records.len() >= lookup.quorum.get() => Ok(lookup.records),
+            Some(err) => Err(err),
+            None => Err(GetRecordError::QuorumFailed {
+                key: lookup.key,
+                records: lookup.records,
+                quorum: lookup.quorum,
+            }),
+        };
+        match result {
+            Ok(records) => {
+                let key = records.first().map(|r| r.record.key.clone());
+                let winner = self.resolve_records(records);
+                match self.pending.remove(&id) {
+                    Some(PendingQuery::ReadForDelete { key, quorum }) => match winner {
+                        Some(winner) if winner.kind == Kind::Tombstone => {
+                            println!("{} is already deleted", self.display.render(key.as_ref()))
+                        }
+                        Some(winner) => self.finish_delete(key, quorum, Some(&winner)),
+                        None => eprintln!(
+                            "Failed to delete {}: no valid current record",
+                            self.display.render(key.as_ref())
+                        ),
+                    },
+                    Some(PendingQuery::Cas(cas)) => match winner {
+                        Some(winner) => self.finish_cas(cas, Some(&winner)),
+                        None => eprintln!(
+                            "Compare-and-swap on {} failed: no valid current record",
+                            self.display.render(cas.key.as_ref())
+                        ),
+                    },
+                    Some(PendingQuery::GetChunk { key: value_key, index }) => {
+                        if let Some(key) = key {
+                            self.chunk_fetched(value_key, index, key, winner);
+                        }
+                    }
+                    _ => match (key, winner) {
+                        (Some(key), Some(winner)) if winner.kind == Kind::Manifest => {
+                            self.fetch_chunks(key, winner)
+                        }
+                        (Some(key), Some(winner)) => self.print_record(&key, &winner),
+                        _ => {}
+                    },
+                }
+            }
+            Err(err) => match (self.pending.remove(&id), err) {
+                (
+                    Some(PendingQuery::ReadForDelete { key, quorum }),
+                    GetRecordError::NotFound { .. },
+                ) => self.delete_unreplicated(key, quorum, Vec::new()),
+                (
+                    Some(PendingQuery::ReadForDelete { key, quorum }),
+                    GetRecordError::QuorumFailed { records, .. },
+                ) => self.delete_unreplicated(key, quorum, records),
+                (Some(PendingQuery::ReadForDelete { key, .. }), err) => {
+                    eprintln!(
+                        "Failed to delete {}: cannot read the current version: {:?}",
+                        self.display.render(key.as_ref()),
+                        err
+                    );
+                }
+                (Some(PendingQuery::Cas(cas)), GetRecordError::NotFound { .. }) => {
+                    self.finish_cas(cas, None)
+                }
+                (Some(PendingQuery::Cas(cas)), err) => {
+                    eprintln!(
+                        "Compare-and-swap on {} failed: cannot read the current value: {:?}",
+                        self.display.render(cas.key.as_ref()),
+                        err
+                    );
+                }
+                (Some(PendingQuery::GetChunk { key, index }), err) => {
+                    if self.downloads.remove(&key).is_some() {
+                        eprintln!(
+                            "Failed to get chunk {} of {}: {:?}",
+                            index,
+                            self.display.render(key.as_ref()),
+                            err
+                        );
+                    }
+                }
+                (_, GetRecordError::QuorumFailed { key, records, quorum }) => {
+                    eprintln!(
+                        "Failed to get record {}: {} of {} required peers answered",
+                        self.display.render(key.as_ref()),
+                        records.len(),
+                        quorum
+                    );
+                }
+                (_, err) => {
+                    eprintln!("Failed to get record: {:?}", err);
+                }
+            },
+        }
+    }
+}
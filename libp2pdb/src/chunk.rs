@@ -0,0 +1,212 @@
+//! Splitting values too large for a single record.
+//!
+//! Kademlia messages are limited to 16 KiB, so a value larger than
+//! [`CHUNK_SIZE`] is stored as content-addressed `/cas/` chunk records plus
+//! a signed record of kind [`Kind::Manifest`] under the user's key:
+//!
+//! ```text
+//! length | sha256 | count | chunk multihash*
+//! ```
+//!
+//! A GET that finds a manifest fetches the chunks, each verified against its
+//! key by [`ContentValidator`], and checks the reassembled value against the
+//! manifest's length and hash. Chunks are immutable: overwriting or deleting
+//! the key replaces the manifest and removes the chunks no other local
+//! manifest lists from the local store. With nobody republishing them, other
+//! peers' copies expire after the record TTL.
+//!
+//! [`Kind::Manifest`]: crate::envelope::Kind::Manifest
+//! [`ContentValidator`]: crate::validator::ContentValidator
+
+use crate::{
+    codec::{invalid_data, Reader, Writer},
+    envelope::Envelope,
+    validator,
+};
+use libp2p::kad::RecordKey as Key;
+use sha2::{Digest, Sha256};
+use std::io;
+
+/// Values larger than this are chunked. Leaves room in a 16 KiB Kademlia
+/// message for the envelope and the closer peers sent along with a record.
+pub const CHUNK_SIZE: usize = 8 * 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Manifest {
+    pub len: u64,
+    /// SHA-256 of the whole value.
+    pub hash: Vec<u8>,
+    /// Multihashes of the chunks, in order.
+    pub chunks: Vec<Vec<u8>>,
+}
+
+impl Manifest {
+    /// Splits `value` into chunks, returning the manifest and the chunks.
+    pub fn split(value: &[u8]) -> Result<(Manifest, Vec<&[u8]>), String> {
+        let chunks: Vec<&[u8]> = value.chunks(CHUNK_SIZE).collect();
+        let manifest = Manifest {
+            len: value.len() as u64,
+            hash: Sha256::digest(value).to_vec(),
+            chunks: chunks
+                .iter()
+                .map(|chunk| validator::sha256_multihash(chunk).to_bytes())
+                .collect(),
+        };
+        // The manifest has to fit in a record itself.
+        if manifest.encode().len() > CHUNK_SIZE {
+            return Err(format!(
+                "value of {} bytes is too large to store, the limit is about {} bytes",
+                value.len(),
+                CHUNK_SIZE / (manifest.encode().len() / chunks.len()) * CHUNK_SIZE
+            ));
+        }
+        Ok((manifest, chunks))
+    }
+
+    /// The keys of the chunk records.
+    pub fn chunk_keys(&self) -> impl Iterator<Item = Key> + '_ {
+        self.chunks.iter().map(|hash| validator::multihash_key(hash))
+    }
+
+    /// Checks a reassembled value against the manifest.
+    pub fn verify(&self, value: &[u8]) -> Result<(), String> {
+        if value.len() as u64 != self.len {
+            return Err(format!(
+                "expected {} bytes, got {}",
+                self.len,
+                value.len()
+            ));
+        }
+        if Sha256::digest(value).as_slice() != self.hash {
+            return Err("value does not match its hash".to_string());
+        }
+        Ok(())
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut w = Writer::new();
+        w.u64(self.len)
+            .bytes(&self.hash)
+            .u32(self.chunks.len() as u32);
+        for hash in &self.chunks {
+            w.bytes(hash);
+        }
+        w.into_inner()
+    }
+
+    pub fn decode(data: &[u8]) -> io::Result<Manifest> {
+        let mut r = Reader::new(data);
+        let len = r.u64()?;
+        let hash = r.bytes()?.to_vec();
+        let mut chunks = Vec::new();
+        for _ in 0..r.u32()? {
+            chunks.push(r.bytes()?.to_vec());
+        }
+        if !r.is_empty() {
+            return Err(invalid_data("trailing bytes after manifest"));
+        }
+        // Only values too large for a single record are chunked, and a
+        // download without chunks would never complete.
+        if chunks.is_empty() {
+            return Err(invalid_data("manifest lists no chunks"));
+        }
+        Ok(Manifest { len, hash, chunks })
+    }
+}
+
+/// A chunked value being fetched for a GET.
+pub struct Download {
+    /// The manifest record, reported once the value is complete.
+    pub envelope: Envelope,
+    pub manifest: Manifest,
+    pub chunks: Vec<Option<Vec<u8>>>,
+}
+
+impl Download {
+    pub fn new(envelope: Envelope, manifest: Manifest) -> Self {
+        Download {
+            chunks: vec![None; manifest.chunks.len()],
+            envelope,
+            manifest,
+        }
+    }
+
+    /// The reassembled value, once every chunk has arrived.
+    pub fn value(&self) -> Option<Vec<u8>> {
+        let mut value = Vec::new();
+        for chunk in &self.chunks {
+            value.extend_from_slice(chunk.as_ref()?);
+        }
+        Some(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A value spanning a few chunks, the last one partial.
+    fn value() -> Vec<u8> {
+        (0..3 * CHUNK_SIZE + 100).map(|i| i as u8).collect()
+    }
+
+    #[test]
+    fn splits_and_verifies() {
+        let value = value();
+        let (manifest, chunks) = Manifest::split(&value).unwrap();
+        assert_eq!(manifest.len, value.len() as u64);
+        assert_eq!(chunks.len(), 4);
+        assert_eq!(chunks.concat(), value);
+        for (key, chunk) in manifest.chunk_keys().zip(&chunks) {
+            assert_eq!(key, validator::content_key(chunk));
+        }
+        assert_eq!(manifest.verify(&value), Ok(()));
+        assert_eq!(Manifest::decode(&manifest.encode()).unwrap(), manifest);
+    }
+
+    #[test]
+    fn rejects_values_of_the_wrong_length() {
+        let value = value();
+        let (manifest, _) = Manifest::split(&value).unwrap();
+        assert_eq!(
+            manifest.verify(&value[1..]),
+            Err(format!("expected {} bytes, got {}", value.len(), value.len() - 1))
+        );
+    }
+
+    #[test]
+    fn rejects_values_not_matching_their_hash() {
+        let mut value = value();
+        let (manifest, _) = Manifest::split(&value).unwrap();
+        value[0] ^= 0xff;
+        assert_eq!(
+            manifest.verify(&value),
+            Err("value does not match its hash".to_string())
+        );
+    }
+
+    #[test]
+    fn rejects_values_whose_manifest_does_not_fit() {
+        let value = vec![0; 256 * CHUNK_SIZE];
+        let err = Manifest::split(&value).unwrap_err();
+        assert!(err.contains("too large to store"), "{}", err);
+    }
+
+    #[test]
+    fn rejects_manifests_without_chunks() {
+        let manifest = Manifest {
+            len: 0,
+            hash: Sha256::digest(b"").to_vec(),
+            chunks: Vec::new(),
+        };
+        assert!(Manifest::decode(&manifest.encode()).is_err());
+    }
+
+    #[test]
+    fn rejects_trailing_bytes() {
+        let (manifest, _) = Manifest::split(&value()).unwrap();
+        let mut data = manifest.encode();
+        data.push(0);
+        assert!(Manifest::decode(&data).is_err());
+    }
+}
@@ -0,0 +1,449 @@
+//! Tokenizing REPL input lines.
+//!
+//! Arguments are separated by whitespace. Double quotes group words and
+//! understand the escapes `\\`, `\"`, `\n`, `\r`, `\t`, `\0`, `\xNN` and
+//! `\u{NNNN}`, which covers everything Rust's `Debug` output for strings uses;
+//! single quotes group words literally; outside quotes a backslash escapes the
+//! next character. Unquoted arguments may also use an encoding prefix:
+//! `hex:<hex>`, `base64:<base64>` or, for values, `@<path>` to read a file.
+//! Quote an argument to store such a prefix literally.
+//!
+//! Commands may end in `name=value` options, such as `ttl=10m`.
+
+use libp2p::kad::{Quorum, RecordKey as Key};
+use std::{
+    collections::HashMap,
+    fs,
+    num::NonZeroUsize,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Token {
+    raw: Vec<u8>,
+    quoted: bool,
+}
+
+impl Token {
+    /// The token as text, for command names, options and addresses.
+    pub fn as_str(&self) -> Option<&str> {
+        std::str::from_utf8(&self.raw).ok()
+    }
+
+    /// Decodes the token as a byte string, honouring `hex:` and `base64:`.
+    pub fn bytes(&self) -> Result<Vec<u8>, String> {
+        if self.quoted {
+            return Ok(self.raw.clone());
+        }
+        if let Some(hex) = self.raw.strip_prefix(b"hex:") {
+            return hex::decode(hex).map_err(|e| format!("invalid hex: {}", e));
+        }
+        if let Some(b64) = self.raw.strip_prefix(b"base64:") {
+            return base64::decode(b64).map_err(|e| format!("invalid base64: {}", e));
+        }
+        Ok(self.raw.clone())
+    }
+
+    pub fn key(&self) -> Result<Key, String> {
+        self.bytes().map(Key::from)
+    }
+
+    /// Like [`Token::bytes`], but `@<path>` reads the value from a file.
+    pub fn value(&self) -> Result<Vec<u8>, String> {
+        if !self.quoted {
+            if let Some(path) = self.raw.strip_prefix(b"@") {
+                let path = std::str::from_utf8(path).map_err(|e| e.to_string())?;
+                return fs::read(path).map_err(|e| format!("cannot read {:?}: {}", path, e));
+            }
+        }
+        self.bytes()
+    }
+}
+
+/// Parses the remaining arguments as `name=value` options, rejecting names
+/// not in `allowed`.
+pub fn options<'a>(
+    args: impl Iterator<Item = &'a Token>,
+    allowed: &[&str],
+) -> Result<HashMap<String, String>, String> {
+    let mut options = HashMap::new();
+    for arg in args {
+        let (name, value) = arg
+            .as_str()
+            .filter(|_| !arg.quoted)
+            .and_then(|arg| arg.split_once('='))
+            .ok_or_else(|| {
+                format!(
+                    "unexpected argument {:?}, quote values containing spaces",
+                    String::from_utf8_lossy(&arg.raw)
+                )
+            })?;
+        if !allowed.contains(&name) {
+            return Err(format!("unknown option {:?}", name));
+        }
+        options.insert(name.to_string(), value.to_string());
+    }
+    Ok(options)
+}
+
+/// Takes the next argument as a key.
+pub fn key_arg<'a>(args: &mut impl Iterator<Item = &'a Token>) -> Result<Key, String> {
+    match args.next() {
+        Some(key) => key.key().map_err(|e| format!("Invalid key: {}", e)),
+        None => Err("Expected key".to_string()),
+    }
+}
+
+/// Takes the next argument as a value, which may be read from a file.
+pub fn value_arg<'a>(args: &mut impl Iterator<Item = &'a Token>) -> Result<Vec<u8>, String> {
+    match args.next() {
+        Some(value) => value.value().map_err(|e| format!("Invalid value: {}", e)),
+        None => Err("Expected value".to_string()),
+    }
+}
+
+/// Takes the next argument as a byte string called `name` in errors.
+pub fn bytes_arg<'a>(
+    args: &mut impl Iterator<Item = &'a Token>,
+    name: &str,
+) -> Result<Vec<u8>, String> {
+    match args.next() {
+        Some(arg) => arg.bytes().map_err(|e| format!("Invalid {}: {}", name, e)),
+        None => Err(format!("Expected {}", name)),
+    }
+}
+
+/// The `quorum=` option, or `default` if it is not set.
+pub fn quorum_option(options: &HashMap<String, String>, default: Quorum) -> Result<Quorum, String> {
+    match options.get("quorum") {
+        Some(quorum) => parse_quorum(quorum).map_err(|e| format!("Invalid quorum: {}", e)),
+        None => Ok(default),
+    }
+}
+
+/// The expiry set by the `ttl=` option, if any.
+pub fn ttl_option(options: &HashMap<String, String>) -> Result<Option<Instant>, String> {
+    options
+        .get("ttl")
+        .map(|ttl| parse_ttl(ttl).map_err(|e| format!("Invalid ttl: {}", e)))
+        .transpose()
+}
+
+/// Parses durations such as `90`, `90s`, `15m`, `2h` or `7d`. A bare number
+/// is in seconds.
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    let (digits, unit) = s.split_at(s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len()));
+    let n: u64 = digits
+        .parse()
+        .map_err(|_| format!("invalid duration {:?}", s))?;
+    let secs = match unit {
+        "" | "s" => Some(n),
+        "m" => n.checked_mul(60),
+        "h" => n.checked_mul(60 * 60),
+        "d" => n.checked_mul(24 * 60 * 60),
+        _ => return Err(format!("invalid duration unit {:?}", unit)),
+    };
+    secs.map(Duration::from_secs)
+        .ok_or_else(|| format!("duration {:?} is too long", s))
+}
+
+/// Parses a `ttl` option into the time the record expires.
+pub fn parse_ttl(s: &str) -> Result<Instant, String> {
+    let ttl = parse_duration(s)?;
+    // The record would have expired before it is stored.
+    if ttl.is_zero() {
+        return Err(format!("ttl {:?} must be greater than zero", s));
+    }
+    // Expiry is also signed and persisted as milliseconds since the epoch.
+    SystemTime::now()
+        .checked_add(ttl)
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .and_then(|t| u64::try_from(t.as_millis()).ok())
+        .and_then(|_| Instant::now().checked_add(ttl))
+        .ok_or_else(|| format!("ttl {:?} is too long", s))
+}
+
+/// Parses a quorum: `one`, `majority`, `all` or a number of peers.
+pub fn parse_quorum(s: &str) -> Result<Quorum, String> {
+    match s.to_ascii_lowercase().as_str() {
+        "one" => Ok(Quorum::One),
+        "majority" => Ok(Quorum::Majority),
+        "all" => Ok(Quorum::All),
+        n => n.parse::<NonZeroUsize>().map(Quorum::N).map_err(|_| {
+            format!(
+                "invalid quorum {:?}, expected one, majority, all or a positive number",
+                s
+            )
+        }),
+    }
+}
+
+/// Splits `line` into tokens.
+pub fn tokenize(line: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = line.chars().peekable();
+
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        if chars.peek().is_none() {
+            return Ok(tokens);
+        }
+
+        let mut token = Token {
+            raw: Vec::new(),
+            quoted: false,
+        };
+        while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+            match c {
+                '"' => {
+                    token.quoted = true;
+                    loop {
+                        match chars.next() {
+                            Some('"') => break,
+                            Some('\\') => unescape(&mut chars, &mut token.raw)?,
+                            Some(c) => push_char(&mut token.raw, c),
+                            None => return Err("unterminated double quote".to_string()),
+                        }
+                    }
+                }
+                '\'' => {
+                    token.quoted = true;
+                    loop {
+                        match chars.next() {
+                            Some('\'') => break,
+                            Some(c) => push_char(&mut token.raw, c),
+                            None => return Err("unterminated single quote".to_string()),
+                        }
+                    }
+                }
+                '\\' => match chars.next() {
+                    Some(c) => push_char(&mut token.raw, c),
+                    None => return Err("trailing backslash".to_string()),
+                },
+                c => push_char(&mut token.raw, c),
+            }
+        }
+        tokens.push(token);
+    }
+}
+
+fn unescape(chars: &mut impl Iterator<Item = char>, out: &mut Vec<u8>) -> Result<(), String> {
+    match chars.next() {
+        Some('n') => out.push(b'\n'),
+        Some('r') => out.push(b'\r'),
+        Some('t') => out.push(b'\t'),
+        Some('0') => out.push(0),
+        Some('x') => {
+            let digits: String = chars.take(2).collect();
+            let byte = u8::from_str_radix(&digits, 16)
+                .map_err(|_| format!("invalid escape \\x{}", digits))?;
+            out.push(byte);
+        }
+        Some('u') => {
+            let digits: String = match chars.next() {
+                Some('{') => chars.take_while(|&c| c != '}').collect(),
+                _ => return Err("invalid escape \\u, expected \\u{NNNN}".to_string()),
+            };
+            let c = u32::from_str_radix(&digits, 16)
+                .ok()
+                .and_then(char::from_u32)
+                .ok_or_else(|| format!("invalid escape \\u{{{}}}", digits))?;
+            push_char(out, c);
+        }
+        Some(c @ ('\\' | '"')) => push_char(out, c),
+        Some(c) => return Err(format!("unknown escape \\{}", c)),
+        None => return Err("unterminated double quote".to_string()),
+    }
+    Ok(())
+}
+
+fn push_char(out: &mut Vec<u8>, c: char) {
+    let mut buf = [0; 4];
+    out.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::display::DisplayMode;
+
+    fn raw(line: &str) -> Vec<Vec<u8>> {
+        tokenize(line).unwrap().into_iter().map(|t| t.raw).collect()
+    }
+
+    #[test]
+    fn splits_on_whitespace() {
+        assert_eq!(
+            raw("  PUT  k\tv \n"),
+            vec![b"PUT".to_vec(), b"k".to_vec(), b"v".to_vec()]
+        );
+        assert!(tokenize("   ").unwrap().is_empty());
+    }
+
+    #[test]
+    fn quotes_group_words() {
+        assert_eq!(
+            raw(r#"PUT "a key" 'a value'"#)[1..],
+            [b"a key".to_vec(), b"a value".to_vec()]
+        );
+        // Quoted and unquoted parts of one argument are joined.
+        assert_eq!(raw(r#"a"b c"'d e'"#), vec![b"ab cd e".to_vec()]);
+        assert_eq!(raw(r#""""#), vec![Vec::new()]);
+    }
+
+    #[test]
+    fn double_quotes_unescape() {
+        assert_eq!(
+            raw(r#""\\ \" \n \r \t \0 \x7f \xFF""#),
+            vec![b"\\ \" \n \r \t \0 \x7f \xff".to_vec()]
+        );
+        assert!(tokenize(r#""\q""#).unwrap_err().contains("unknown escape"));
+        assert!(tokenize(r#""\xg0""#)
+            .unwrap_err()
+            .contains("invalid escape"));
+    }
+
+    #[test]
+    fn unescapes_unicode() {
+        assert_eq!(
+            raw(r#""\u{1b}[0m \u{e9}""#),
+            vec!["\u{1b}[0m \u{e9}".as_bytes().to_vec()]
+        );
+        assert!(tokenize(r#""\u1b""#)
+            .unwrap_err()
+            .contains("invalid escape"));
+        assert!(tokenize(r#""\u{d800}""#)
+            .unwrap_err()
+            .contains("invalid escape"));
+    }
+
+    #[test]
+    fn reads_back_rendered_text() {
+        for text in [
+            "plain",
+            "tab\tquote\"back\\slash",
+            "\u{1b}[31mred\u{7f}",
+            "\u{200b}\u{301}e\0",
+        ] {
+            let rendered = DisplayMode::Auto.render(text.as_bytes());
+            let tokens = tokenize(&rendered).unwrap();
+            assert_eq!(tokens.len(), 1, "{}", rendered);
+            assert_eq!(tokens[0].bytes().unwrap(), text.as_bytes(), "{}", rendered);
+        }
+    }
+
+    #[test]
+    fn single_quotes_are_literal() {
+        assert_eq!(raw(r#"'\n "x"'"#), vec![br#"\n "x""#.to_vec()]);
+    }
+
+    #[test]
+    fn backslash_escapes_outside_quotes() {
+        assert_eq!(raw(r"a\ b \'c"), vec![b"a b".to_vec(), b"'c".to_vec()]);
+        assert_eq!(tokenize(r"a\").unwrap_err(), "trailing backslash");
+    }
+
+    #[test]
+    fn rejects_unterminated_quotes() {
+        assert_eq!(
+            tokenize(r#""abc"#).unwrap_err(),
+            "unterminated double quote"
+        );
+        assert_eq!(tokenize("'abc").unwrap_err(), "unterminated single quote");
+    }
+
+    #[test]
+    fn decodes_prefixes_unless_quoted() {
+        let tokens = tokenize(r#"hex:6869 base64:aGk= "hex:6869" hex:zz"#).unwrap();
+        assert_eq!(tokens[0].bytes().unwrap(), b"hi");
+        assert_eq!(tokens[1].bytes().unwrap(), b"hi");
+        assert_eq!(tokens[2].bytes().unwrap(), b"hex:6869");
+        assert!(tokens[3].bytes().unwrap_err().starts_with("invalid hex"));
+    }
+
+    #[test]
+    fn reads_values_from_files() {
+        let path = std::env::temp_dir().join(format!("libp2pdb-command-{}", std::process::id()));
+        fs::write(&path, b"from a file").unwrap();
+        let line = format!("@{} '@{}'", path.display(), path.display());
+        let tokens = tokenize(&line).unwrap();
+        assert_eq!(tokens[0].value().unwrap(), b"from a file");
+        assert_eq!(
+            tokens[0].bytes().unwrap(),
+            line.split(' ').next().unwrap().as_bytes()
+        );
+        assert_eq!(
+            tokens[1].value().unwrap(),
+            format!("@{}", path.display()).as_bytes()
+        );
+        fs::remove_file(&path).unwrap();
+        assert!(tokens[0].value().unwrap_err().starts_with("cannot read"));
+    }
+
+    #[test]
+    fn parses_options() {
+        let tokens = tokenize("ttl=10m quorum=all").unwrap();
+        let options = options(tokens.iter(), &["ttl", "quorum"]).unwrap();
+        assert_eq!(options["ttl"], "10m");
+        assert_eq!(options["quorum"], "all");
+
+        let tokens = tokenize("seq=1").unwrap();
+        assert_eq!(
+            super::options(tokens.iter(), &["ttl"]).unwrap_err(),
+            r#"unknown option "seq""#
+        );
+        // A quoted argument is never an option.
+        let tokens = tokenize("'ttl=1'").unwrap();
+        assert!(super::options(tokens.iter(), &["ttl"])
+            .unwrap_err()
+            .starts_with("unexpected argument"));
+    }
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!(parse_duration("90").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_duration("90s").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_duration("15m").unwrap(), Duration::from_secs(15 * 60));
+        assert_eq!(
+            parse_duration("2h").unwrap(),
+            Duration::from_secs(2 * 60 * 60)
+        );
+        assert_eq!(
+            parse_duration("7d").unwrap(),
+            Duration::from_secs(7 * 24 * 60 * 60)
+        );
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("m").is_err());
+        assert!(parse_duration("10y").is_err());
+        assert!(parse_duration("999999999999999999d").is_err());
+        assert!(parse_duration("18446744073709551616").is_err());
+    }
+
+    #[test]
+    fn rejects_ttls_past_the_end_of_time() {
+        let expires = parse_ttl("1h").unwrap();
+        assert!(expires > Instant::now() + Duration::from_secs(59 * 60));
+        assert!(parse_ttl("18446744073709551615").is_err());
+        assert!(parse_ttl("9223372036854775000").is_err());
+    }
+
+    #[test]
+    fn rejects_zero_ttls() {
+        assert!(parse_ttl("0").is_err());
+        assert!(parse_ttl("0m").is_err());
+        assert!(parse_ttl("1s").is_ok());
+    }
+
+    #[test]
+    fn parses_quorums() {
+        assert_eq!(parse_quorum("One").unwrap(), Quorum::One);
+        assert_eq!(parse_quorum("majority").unwrap(), Quorum::Majority);
+        assert_eq!(parse_quorum("ALL").unwrap(), Quorum::All);
+        assert_eq!(
+            parse_quorum("3").unwrap(),
+            Quorum::N(NonZeroUsize::new(3).unwrap())
+        );
+        assert!(parse_quorum("0").is_err());
+        assert!(parse_quorum("some").is_err());
+    }
+}
@@ -0,0 +1,507 @@
+//! Conflict-free replicated data types stored as records.
+//!
+//! A CRDT record is a signed record of kind [`Kind::Crdt`] whose payload is
+//! one of the types below. Replicas written concurrently by different peers
+//! are merged rather than resolved to a winner, so no update is lost. Each
+//! payload starts with a type tag:
+//!
+//! ```text
+//! g-counter:    0 | count | (peer | n)*
+//! pn-counter:   1 | g-counter increments | g-counter decrements
+//! lww-register: 2 | timestamp | writer | value
+//! or-set:       3 | count | (member | count | tag*)* | count | removed tag*
+//! ```
+//!
+//! where a tag is `peer | n`. Merging is only defined between replicas of
+//! the same type.
+//!
+//! # Trust
+//!
+//! The envelope signature only says who published a replica, not who wrote
+//! what is in it: anyone allowed to publish under a key can claim counts or
+//! tags for other peers, or date a register write arbitrarily far ahead.
+//! Such a write then wins every merge until the clocks catch up, and a
+//! register timestamp or set tag at `u64::MAX` stops that writer from making
+//! further changes. CRDT keys are therefore only as trustworthy as the peers
+//! that can write them; register a [`RecordValidator`] for their prefix
+//! where that matters.
+//!
+//! [`Kind::Crdt`]: crate::envelope::Kind::Crdt
+//! [`RecordValidator`]: crate::validator::RecordValidator
+
+use crate::codec::{invalid_data, Reader, Writer};
+use libp2p::PeerId;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    io,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+const TAG_G_COUNTER: u8 = 0;
+const TAG_PN_COUNTER: u8 = 1;
+const TAG_LWW_REGISTER: u8 = 2;
+const TAG_OR_SET: u8 = 3;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Crdt {
+    GCounter(GCounter),
+    PnCounter(PnCounter),
+    LwwRegister(LwwRegister),
+    OrSet(OrSet),
+}
+
+impl Crdt {
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Crdt::GCounter(_) => "g-counter",
+            Crdt::PnCounter(_) => "pn-counter",
+            Crdt::LwwRegister(_) => "lww-register",
+            Crdt::OrSet(_) => "or-set",
+        }
+    }
+
+    /// Merges `other` into `self`. Fails if they are of different types.
+    pub fn merge(&mut self, other: &Crdt) -> Result<(), String> {
+        match (self, other) {
+            (Crdt::GCounter(a), Crdt::GCounter(b)) => a.merge(b),
+            (Crdt::PnCounter(a), Crdt::PnCounter(b)) => a.merge(b),
+            (Crdt::LwwRegister(a), Crdt::LwwRegister(b)) => a.merge(b),
+            (Crdt::OrSet(a), Crdt::OrSet(b)) => a.merge(b),
+            (a, b) => {
+                return Err(format!(
+                    "cannot merge {} with {}",
+                    a.type_name(),
+                    b.type_name()
+                ))
+            }
+        }
+        Ok(())
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut w = Writer::new();
+        match self {
+            Crdt::GCounter(c) => {
+                w.u8(TAG_G_COUNTER);
+                c.encode(&mut w);
+            }
+            Crdt::PnCounter(c) => {
+                w.u8(TAG_PN_COUNTER);
+                c.increments.encode(&mut w);
+                c.decrements.encode(&mut w);
+            }
+            Crdt::LwwRegister(r) => {
+                w.u8(TAG_LWW_REGISTER)
+                    .u64(r.timestamp)
+                    .bytes(&r.writer.map(|p| p.to_bytes()).unwrap_or_default())
+                    .bytes(&r.value);
+            }
+            Crdt::OrSet(s) => {
+                w.u8(TAG_OR_SET).u32(s.members.len() as u32);
+                for (member, tags) in &s.members {
+                    w.bytes(member).u32(tags.len() as u32);
+                    for tag in tags {
+                        encode_tag(&mut w, tag);
+                    }
+                }
+                w.u32(s.removed.len() as u32);
+                for tag in &s.removed {
+                    encode_tag(&mut w, tag);
+                }
+            }
+        }
+        w.into_inner()
+    }
+
+    pub fn decode(data: &[u8]) -> io::Result<Crdt> {
+        let mut r = Reader::new(data);
+        let crdt = match r.u8()? {
+            TAG_G_COUNTER => Crdt::GCounter(GCounter::decode(&mut r)?),
+            TAG_PN_COUNTER => Crdt::PnCounter(PnCounter {
+                increments: GCounter::decode(&mut r)?,
+                decrements: GCounter::decode(&mut r)?,
+            }),
+            TAG_LWW_REGISTER => Crdt::LwwRegister(LwwRegister {
+                timestamp: r.u64()?,
+                writer: match r.bytes()? {
+                    [] => None,
+                    peer => Some(PeerId::from_bytes(peer).map_err(invalid_data)?),
+                },
+                value: r.bytes()?.to_vec(),
+            }),
+            TAG_OR_SET => {
+                let mut set = OrSet::default();
+                for _ in 0..r.u32()? {
+                    let member = r.bytes()?.to_vec();
+                    let mut tags = BTreeSet::new();
+                    for _ in 0..r.u32()? {
+                        tags.insert(decode_tag(&mut r)?);
+                    }
+                    set.members.insert(member, tags);
+                }
+                for _ in 0..r.u32()? {
+                    set.removed.insert(decode_tag(&mut r)?);
+                }
+                Crdt::OrSet(set)
+            }
+            tag => return Err(invalid_data(format!("unknown CRDT type {}", tag))),
+        };
+        if !r.is_empty() {
+            return Err(invalid_data("trailing bytes after CRDT"));
+        }
+        Ok(crdt)
+    }
+}
+
+/// A counter that only grows. Each peer counts its own increments, and the
+/// value is their sum.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GCounter {
+    counts: BTreeMap<PeerId, u64>,
+}
+
+impl GCounter {
+    pub fn increment(&mut self, peer: PeerId, by: u64) {
+        let count = self.counts.entry(peer).or_default();
+        *count = count.saturating_add(by);
+    }
+
+    pub fn value(&self) -> u64 {
+        self.counts.values().fold(0, |sum, n| sum.saturating_add(*n))
+    }
+
+    fn merge(&mut self, other: &GCounter) {
+        for (peer, n) in &other.counts {
+            let count = self.counts.entry(*peer).or_default();
+            *count = (*count).max(*n);
+        }
+    }
+
+    fn encode(&self, w: &mut Writer) {
+        w.u32(self.counts.len() as u32);
+        for (peer, n) in &self.counts {
+            w.bytes(&peer.to_bytes()).u64(*n);
+        }
+    }
+
+    fn decode(r: &mut Reader) -> io::Result<GCounter> {
+        let mut counter = GCounter::default();
+        for _ in 0..r.u32()? {
+            let peer = PeerId::from_bytes(r.bytes()?).map_err(invalid_data)?;
+            counter.counts.insert(peer, r.u64()?);
+        }
+        Ok(counter)
+    }
+}
+
+/// A counter that can also be decremented, as a pair of [`GCounter`]s.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PnCounter {
+    increments: GCounter,
+    decrements: GCounter,
+}
+
+impl PnCounter {
+    pub fn increment(&mut self, peer: PeerId, by: u64) {
+        self.increments.increment(peer, by);
+    }
+
+    pub fn decrement(&mut self, peer: PeerId, by: u64) {
+        self.decrements.increment(peer, by);
+    }
+
+    pub fn value(&self) -> i128 {
+        self.increments.value() as i128 - self.decrements.value() as i128
+    }
+
+    fn merge(&mut self, other: &PnCounter) {
+        self.increments.merge(&other.increments);
+        self.decrements.merge(&other.decrements);
+    }
+}
+
+/// A single value where the most recent write wins. Writes in the same
+/// millisecond are ordered by writer.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LwwRegister {
+    /// Milliseconds since the epoch.
+    timestamp: u64,
+    writer: Option<PeerId>,
+    value: Vec<u8>,
+}
+
+impl LwwRegister {
+    pub fn set(&mut self, peer: PeerId, value: Vec<u8>) {
+        // Never go back in time, even if our clock is behind the last writer's.
+        self.timestamp = now_ms().max(self.timestamp.saturating_add(1));
+        self.writer = Some(peer);
+        self.value = value;
+    }
+
+    pub fn value(&self) -> &[u8] {
+        &self.value
+    }
+
+    fn merge(&mut self, other: &LwwRegister) {
+        if (other.timestamp, other.writer) > (self.timestamp, self.writer) {
+            *self = other.clone();
+        }
+    }
+}
+
+/// Tags a single add to an [`OrSet`]: the adding peer and its add count.
+type Tag = (PeerId, u64);
+
+/// An observed-remove set. Every add gets a unique tag, and a remove only
+/// removes the tags it has seen, so an add concurrent with a remove wins.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct OrSet {
+    members: BTreeMap<Vec<u8>, BTreeSet<Tag>>,
+    removed: BTreeSet<Tag>,
+}
+
+impl OrSet {
+    pub fn add(&mut self, peer: PeerId, member: Vec<u8>) {
+        let next = self
+            .members
+            .values()
+            .flatten()
+            .chain(&self.removed)
+            .filter(|(p, _)| *p == peer)
+            .map(|(_, n)| n.saturating_add(1))
+            .max()
+            .unwrap_or(1);
+        self.members.entry(member).or_default().insert((peer, next));
+    }
+
+    /// Removes `member`, returning whether it was present.
+    pub fn remove(&mut self, member: &[u8]) -> bool {
+        let present = self.contains(member);
+        if let Some(tags) = self.members.remove(member) {
+            self.removed.extend(tags);
+        }
+        present
+    }
+
+    pub fn contains(&self, member: &[u8]) -> bool {
+        self.members
+            .get(member)
+            .is_some_and(|tags| tags.iter().any(|tag| !self.removed.contains(tag)))
+    }
+
+    pub fn members(&self) -> impl Iterator<Item = &[u8]> {
+        self.members
+            .keys()
+            .filter(|member| self.contains(member))
+            .map(Vec::as_slice)
+    }
+
+    fn merge(&mut self, other: &OrSet) {
+        self.removed.extend(&other.removed);
+        for (member, tags) in &other.members {
+            self.members.entry(member.clone()).or_default().extend(tags);
+        }
+        // Drop tags that are removed, and members left without tags.
+        let removed = &self.removed;
+        self.members.retain(|_, tags| {
+            tags.retain(|tag| !removed.contains(tag));
+            !tags.is_empty()
+        });
+    }
+}
+
+fn encode_tag(w: &mut Writer, (peer, n): &Tag) {
+    w.bytes(&peer.to_bytes()).u64(*n);
+}
+
+fn decode_tag(r: &mut Reader) -> io::Result<Tag> {
+    let peer = PeerId::from_bytes(r.bytes()?).map_err(invalid_data)?;
+    Ok((peer, r.u64()?))
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn merged(a: &Crdt, b: &Crdt) -> Crdt {
+        let mut merged = a.clone();
+        merged.merge(b).unwrap();
+        merged
+    }
+
+    /// Checks that merging is commutative, associative and idempotent.
+    fn assert_merge_laws(a: &Crdt, b: &Crdt, c: &Crdt) {
+        assert_eq!(merged(a, b), merged(b, a));
+        assert_eq!(merged(&merged(a, b), c), merged(a, &merged(b, c)));
+        assert_eq!(merged(a, a), *a);
+        assert_eq!(merged(&merged(a, b), b), merged(a, b));
+    }
+
+    fn assert_round_trips(crdt: &Crdt) {
+        assert_eq!(Crdt::decode(&crdt.encode()).unwrap(), *crdt);
+    }
+
+    fn register(timestamp: u64, writer: PeerId, value: &[u8]) -> Crdt {
+        Crdt::LwwRegister(LwwRegister {
+            timestamp,
+            writer: Some(writer),
+            value: value.to_vec(),
+        })
+    }
+
+    #[test]
+    fn g_counters_merge() {
+        let (p, q) = (PeerId::random(), PeerId::random());
+        let mut a = GCounter::default();
+        a.increment(p, 3);
+        let mut b = a.clone();
+        b.increment(p, 2);
+        b.increment(q, 1);
+        let mut c = GCounter::default();
+        c.increment(q, 4);
+
+        let [a, b, c] = [a, b, c].map(Crdt::GCounter);
+        assert_merge_laws(&a, &b, &c);
+        match merged(&merged(&a, &b), &c) {
+            Crdt::GCounter(counter) => assert_eq!(counter.value(), 9),
+            other => panic!("merged into {:?}", other),
+        }
+    }
+
+    #[test]
+    fn pn_counters_merge() {
+        let (p, q) = (PeerId::random(), PeerId::random());
+        let mut a = PnCounter::default();
+        a.increment(p, 5);
+        let mut b = a.clone();
+        b.decrement(p, 2);
+        let mut c = PnCounter::default();
+        c.decrement(q, 7);
+
+        let [a, b, c] = [a, b, c].map(Crdt::PnCounter);
+        assert_merge_laws(&a, &b, &c);
+        match merged(&merged(&a, &b), &c) {
+            Crdt::PnCounter(counter) => assert_eq!(counter.value(), -4),
+            other => panic!("merged into {:?}", other),
+        }
+    }
+
+    #[test]
+    fn lww_registers_merge() {
+        let (p, q) = (PeerId::random(), PeerId::random());
+        let a = register(1, p, b"old");
+        let b = register(2, p, b"new");
+        // Same millisecond as `b`, ordered by writer instead.
+        let c = register(2, q, b"other");
+
+        assert_merge_laws(&a, &b, &c);
+        let expected = if q > p { &c } else { &b };
+        assert_eq!(merged(&merged(&a, &b), &c), *expected);
+    }
+
+    #[test]
+    fn or_sets_merge() {
+        let (p, q) = (PeerId::random(), PeerId::random());
+        let mut a = OrSet::default();
+        a.add(p, b"x".to_vec());
+        a.add(p, b"y".to_vec());
+        let mut b = a.clone();
+        b.remove(b"x");
+        b.add(p, b"z".to_vec());
+        let mut c = OrSet::default();
+        c.add(q, b"y".to_vec());
+
+        let [a, b, c] = [a, b, c].map(Crdt::OrSet);
+        assert_merge_laws(&a, &b, &c);
+        match merged(&merged(&a, &b), &c) {
+            Crdt::OrSet(set) => {
+                let members: Vec<_> = set.members().collect();
+                assert_eq!(members, [b"y".as_slice(), b"z".as_slice()]);
+            }
+            other => panic!("merged into {:?}", other),
+        }
+    }
+
+    #[test]
+    fn or_set_add_wins_over_concurrent_remove() {
+        let (p, q) = (PeerId::random(), PeerId::random());
+        let mut base = OrSet::default();
+        base.add(p, b"x".to_vec());
+
+        let mut removing = base.clone();
+        assert!(removing.remove(b"x"));
+        let mut adding = base;
+        adding.add(q, b"x".to_vec());
+
+        for (mut set, other) in [(removing.clone(), &adding), (adding.clone(), &removing)] {
+            set.merge(other);
+            assert!(set.contains(b"x"));
+        }
+    }
+
+    #[test]
+    fn or_set_remove_wins_over_seen_adds() {
+        let p = PeerId::random();
+        let mut added = OrSet::default();
+        added.add(p, b"x".to_vec());
+        let mut removed = added.clone();
+        assert!(removed.remove(b"x"));
+        assert!(!removed.remove(b"x"));
+
+        added.merge(&removed);
+        assert!(!added.contains(b"x"));
+    }
+
+    #[test]
+    fn round_trips() {
+        let p = PeerId::random();
+        let mut counter = GCounter::default();
+        counter.increment(p, 3);
+        let mut pn = PnCounter::default();
+        pn.increment(p, 2);
+        pn.decrement(p, 1);
+        let mut set = OrSet::default();
+        set.add(p, b"x".to_vec());
+        set.add(p, b"y".to_vec());
+        set.remove(b"x");
+
+        assert_round_trips(&Crdt::GCounter(GCounter::default()));
+        assert_round_trips(&Crdt::GCounter(counter));
+        assert_round_trips(&Crdt::PnCounter(pn));
+        assert_round_trips(&Crdt::LwwRegister(LwwRegister::default()));
+        assert_round_trips(&register(42, p, b"value"));
+        assert_round_trips(&Crdt::OrSet(set));
+    }
+
+    #[test]
+    fn rejects_trailing_bytes() {
+        let mut data = register(42, PeerId::random(), b"value").encode();
+        data.push(0);
+        assert!(Crdt::decode(&data).is_err());
+    }
+
+    #[test]
+    fn rejects_unknown_types() {
+        let mut data = Crdt::GCounter(GCounter::default()).encode();
+        data[0] = 0xff;
+        assert!(Crdt::decode(&data).is_err());
+    }
+
+    #[test]
+    fn rejects_merging_different_types() {
+        let mut counter = Crdt::GCounter(GCounter::default());
+        let set = Crdt::OrSet(OrSet::default());
+        assert_eq!(
+            counter.merge(&set),
+            Err("cannot merge g-counter with or-set".to_string())
+        );
+        assert_eq!(counter, Crdt::GCounter(GCounter::default()));
+    }
+}
@@ -0,0 +1,30 @@
+//! Rendering keys and values for the terminal.
+//!
+//! Records come from arbitrary peers, so nothing here may assume UTF-8.
+
+use clap::ValueEnum;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum DisplayMode {
+    /// Quoted text when the bytes are valid UTF-8, hex otherwise.
+    #[default]
+    Auto,
+    Hex,
+    Base64,
+}
+
+impl DisplayMode {
+    /// Renders `bytes` in a form that can be pasted back into the REPL.
+    pub fn render(self, bytes: &[u8]) -> String {
+        match self {
+            DisplayMode::Auto => match std::str::from_utf8(bytes) {
+                // `Debug` escapes control characters, so a value cannot mess
+                // with the terminal, and the tokenizer reads its escapes back.
+                Ok(text) => format!("{:?}", text),
+                Err(_) => format!("hex:{}", hex::encode(bytes)),
+            },
+            DisplayMode::Hex => format!("hex:{}", hex::encode(bytes)),
+            DisplayMode::Base64 => format!("base64:{}", base64::encode(bytes)),
+        }
+    }
+}
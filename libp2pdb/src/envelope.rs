@@ -0,0 +1,330 @@
+//! Signed record values.
+//!
+//! Every record this node publishes wraps the user's value in an envelope
+//! signed with the node's identity keypair:
+//!
+//! ```text
+//! MAGIC | version | public key | kind | seq | created_at | expires_at | payload | signature
+//! ```
+//!
+//! The signature covers the record key and every field before it, so a peer
+//! can neither alter the value, its version or its expiry nor move it to
+//! another key. `seq` is the record's version; zero means the record is
+//! unversioned. Times are milliseconds since the epoch, with an `expires_at`
+//! of zero meaning the record does not expire. Version 1 envelopes, which
+//! predate `seq`, are still accepted as unversioned.
+
+use crate::{
+    chunk::Manifest,
+    codec::{invalid_data, Reader, Writer},
+};
+use libp2p::{
+    identity::{Keypair, PublicKey},
+    kad::{Record, RecordKey as Key},
+    PeerId,
+};
+use sha2::{Digest, Sha256};
+use std::{
+    io,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+const MAGIC: &[u8] = b"\0libp2pdb/signed\0";
+const VERSION: u8 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Value,
+    /// Marks the key as deleted. See [`crate::tombstone`].
+    Tombstone,
+    /// A value merged with its concurrent replicas. See [`crate::crdt`].
+    Crdt,
+    /// Lists the chunks of a large value. See [`crate::chunk`].
+    Manifest,
+}
+
+/// The verified contents of a signed record.
+#[derive(Debug, Clone)]
+pub struct Envelope {
+    pub kind: Kind,
+    pub publisher: PeerId,
+    /// Sequence number of a versioned record.
+    pub seq: Option<u64>,
+    pub created_at: u64,
+    pub expires_at: Option<u64>,
+    pub payload: Vec<u8>,
+}
+
+impl Envelope {
+    /// Time since the record was signed.
+    pub fn age(&self) -> Duration {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH + Duration::from_millis(self.created_at))
+            .unwrap_or_default()
+    }
+
+    /// SHA-256 of the value, as compared by `CAS`. `None` for tombstones.
+    /// For a chunked value this is the hash of the whole value.
+    pub fn value_hash(&self) -> Option<Vec<u8>> {
+        match self.kind {
+            Kind::Tombstone => None,
+            Kind::Manifest => Manifest::decode(&self.payload).ok().map(|m| m.hash),
+            _ => Some(Sha256::digest(&self.payload).to_vec()),
+        }
+    }
+
+    /// Whether the signed expiry has passed, whatever expiry the record was
+    /// sent with.
+    pub fn is_expired(&self) -> bool {
+        self.expires_at
+            .is_some_and(|expires_at| expires_at <= unix_ms(SystemTime::now()))
+    }
+
+    /// The signed expiry as an `Instant`, for clamping `Record::expires`.
+    pub fn expires(&self) -> Option<Instant> {
+        let expires_at = UNIX_EPOCH + Duration::from_millis(self.expires_at?);
+        let now = Instant::now();
+        Some(match expires_at.duration_since(SystemTime::now()) {
+            Ok(remaining) => now + remaining,
+            Err(_) => now,
+        })
+    }
+}
+
+/// Builds a record for `key` holding `payload`, signed with `keypair` and
+/// published under its `PeerId`.
+pub fn seal(
+    keypair: &Keypair,
+    key: Key,
+    kind: Kind,
+    seq: Option<u64>,
+    payload: &[u8],
+    expires: Option<Instant>,
+) -> Result<Record, String> {
+    let now = unix_ms(SystemTime::now());
+    let expires_at = expires.map(|t| {
+        let ttl = t.saturating_duration_since(Instant::now()).as_millis();
+        now.saturating_add(u64::try_from(ttl).unwrap_or(u64::MAX))
+    });
+
+    let mut body = Writer::new();
+    body.u8(VERSION)
+        .bytes(&keypair.public().encode_protobuf())
+        .u8(match kind {
+            Kind::Value => 0,
+            Kind::Tombstone => 1,
+            Kind::Crdt => 2,
+            Kind::Manifest => 3,
+        })
+        .u64(seq.unwrap_or(0))
+        .u64(now)
+        .u64(expires_at.unwrap_or(0))
+        .bytes(payload);
+    let body = body.into_inner();
+
+    let signature = keypair
+        .sign(&signed_message(&key, &body))
+        .map_err(|e| e.to_string())?;
+
+    let mut value = MAGIC.to_vec();
+    value.extend_from_slice(&body);
+    let mut sig = Writer::new();
+    sig.bytes(&signature);
+    value.extend(sig.into_inner());
+
+    Ok(Record {
+        key,
+        value,
+        publisher: Some(keypair.public().to_peer_id()),
+        expires,
+    })
+}
+
+/// Decodes `record` and verifies its signature. Fails for records that are
+/// unsigned, tampered with, or published by someone other than the signer.
+pub fn open(record: &Record) -> io::Result<Envelope> {
+    let data = record
+        .value
+        .strip_prefix(MAGIC)
+        .ok_or_else(|| invalid_data("record is not signed"))?;
+
+    let mut r = Reader::new(data);
+    let version = r.u8()?;
+    if version == 0 || version > VERSION {
+        return Err(invalid_data(format!(
+            "unsupported envelope version {}",
+            version
+        )));
+    }
+    let public = PublicKey::try_decode_protobuf(r.bytes()?).map_err(invalid_data)?;
+    let kind = match r.u8()? {
+        0 => Kind::Value,
+        1 => Kind::Tombstone,
+        2 => Kind::Crdt,
+        3 => Kind::Manifest,
+        kind => return Err(invalid_data(format!("unknown record kind {}", kind))),
+    };
+    let seq = match version {
+        1 => None,
+        _ => Some(r.u64()?).filter(|&seq| seq != 0),
+    };
+    let created_at = r.u64()?;
+    let expires_at = Some(r.u64()?).filter(|&t| t != 0);
+    let payload = r.bytes()?.to_vec();
+    let body = &data[..data.len() - r.remaining()];
+    let signature = r.bytes()?;
+    if !r.is_empty() {
+        return Err(invalid_data("trailing bytes after signature"));
+    }
+
+    if !public.verify(&signed_message(&record.key, body), signature) {
+        return Err(invalid_data("invalid signature"));
+    }
+    let publisher = public.to_peer_id();
+    if record.publisher != Some(publisher) {
+        return Err(invalid_data("record not published by its signer"));
+    }
+
+    Ok(Envelope {
+        kind,
+        publisher,
+        seq,
+        created_at,
+        expires_at,
+        payload,
+    })
+}
+
+fn signed_message(key: &Key, body: &[u8]) -> Vec<u8> {
+    let mut w = Writer::new();
+    w.bytes(MAGIC).bytes(key.as_ref());
+    let mut message = w.into_inner();
+    message.extend_from_slice(body);
+    message
+}
+
+fn unix_ms(t: SystemTime) -> u64 {
+    t.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An envelope body with arbitrary fields. Version 1 bodies have no
+    /// `seq`.
+    fn body(keypair: &Keypair, version: u8, seq: u64, expires_at: u64, payload: &[u8]) -> Vec<u8> {
+        let mut w = Writer::new();
+        w.u8(version).bytes(&keypair.public().encode_protobuf()).u8(0);
+        if version != 1 {
+            w.u64(seq);
+        }
+        w.u64(unix_ms(SystemTime::now())).u64(expires_at).bytes(payload);
+        w.into_inner()
+    }
+
+    /// A record carrying `sent`, with a signature over `signed`.
+    fn record(keypair: &Keypair, key: &Key, signed: &[u8], sent: &[u8]) -> Record {
+        let signature = keypair.sign(&signed_message(key, signed)).unwrap();
+        let mut value = MAGIC.to_vec();
+        value.extend_from_slice(sent);
+        let mut sig = Writer::new();
+        sig.bytes(&signature);
+        value.extend(sig.into_inner());
+        Record {
+            key: key.clone(),
+            value,
+            publisher: Some(keypair.public().to_peer_id()),
+            expires: None,
+        }
+    }
+
+    /// An unversioned value signed with `keypair`.
+    fn value(keypair: &Keypair) -> Record {
+        seal(keypair, Key::new(b"key"), Kind::Value, None, b"value", None).unwrap()
+    }
+
+    #[test]
+    fn opens_what_it_seals() {
+        let keypair = Keypair::generate_ed25519();
+        let expires = Instant::now() + Duration::from_secs(60);
+        let key = Key::new(b"key");
+        let record = seal(&keypair, key, Kind::Value, Some(3), b"value", Some(expires)).unwrap();
+
+        let envelope = open(&record).unwrap();
+        assert_eq!(envelope.kind, Kind::Value);
+        assert_eq!(envelope.publisher, keypair.public().to_peer_id());
+        assert_eq!(envelope.seq, Some(3));
+        assert_eq!(envelope.payload, b"value");
+        assert!(envelope.expires_at.is_some());
+        assert!(!envelope.is_expired());
+    }
+
+    #[test]
+    fn rejects_changed_fields() {
+        let keypair = Keypair::generate_ed25519();
+        let key = Key::new(b"key");
+        let signed = body(&keypair, VERSION, 1, 0, b"value");
+        assert!(open(&record(&keypair, &key, &signed, &signed)).is_ok());
+
+        for sent in [
+            body(&keypair, VERSION, 1, 0, b"other"),
+            body(&keypair, VERSION, 2, 0, b"value"),
+            body(&keypair, VERSION, 1, u64::MAX, b"value"),
+        ] {
+            let err = open(&record(&keypair, &key, &signed, &sent)).unwrap_err();
+            assert_eq!(err.to_string(), "invalid signature");
+        }
+    }
+
+    #[test]
+    fn rejects_records_moved_to_another_key() {
+        let keypair = Keypair::generate_ed25519();
+        let mut record = value(&keypair);
+        record.key = Key::new(b"other");
+        assert!(open(&record).is_err());
+    }
+
+    #[test]
+    fn rejects_publishers_other_than_the_signer() {
+        let keypair = Keypair::generate_ed25519();
+        let mut record = value(&keypair);
+        record.publisher = Some(PeerId::random());
+        assert!(open(&record).is_err());
+        record.publisher = None;
+        assert!(open(&record).is_err());
+    }
+
+    #[test]
+    fn rejects_trailing_bytes() {
+        let keypair = Keypair::generate_ed25519();
+        let mut record = value(&keypair);
+        record.value.push(0);
+        assert!(open(&record).is_err());
+    }
+
+    #[test]
+    fn rejects_unknown_versions() {
+        let keypair = Keypair::generate_ed25519();
+        let key = Key::new(b"key");
+        for version in [0, VERSION + 1] {
+            let body = body(&keypair, version, 0, 0, b"value");
+            let err = open(&record(&keypair, &key, &body, &body)).unwrap_err();
+            assert!(err.to_string().contains("unsupported envelope version"));
+        }
+    }
+
+    #[test]
+    fn accepts_version_1_as_unversioned() {
+        let keypair = Keypair::generate_ed25519();
+        let key = Key::new(b"key");
+        let body = body(&keypair, 1, 0, 0, b"value");
+
+        let envelope = open(&record(&keypair, &key, &body, &body)).unwrap();
+        assert_eq!(envelope.seq, None);
+        assert_eq!(envelope.expires_at, None);
+        assert_eq!(envelope.payload, b"value");
+    }
+}
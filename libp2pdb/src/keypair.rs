@@ -0,0 +1,80 @@
+//! Loading and persisting the node's identity keypair.
+
+use crate::codec::invalid_data;
+use libp2p::identity::Keypair;
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::Path,
+};
+
+#[cfg(unix)]
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+
+/// Loads the keypair stored at `path`, generating and saving a new ed25519
+/// keypair on first run so the node keeps its `PeerId` across restarts.
+pub fn load_or_generate(path: &Path) -> io::Result<Keypair> {
+    match fs::read(path) {
+        Ok(bytes) => {
+            check_permissions(path)?;
+            Keypair::from_protobuf_encoding(&bytes).map_err(invalid_data)
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            let keypair = Keypair::generate_ed25519();
+            save(path, &keypair)?;
+            println!("Generated new identity in {:?}", path);
+            Ok(keypair)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Writes the keypair to a temporary file and renames it into place, so a
+/// crash never leaves a truncated key file behind.
+fn save(path: &Path, keypair: &Keypair) -> io::Result<()> {
+    let dir = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    fs::create_dir_all(dir)?;
+    let bytes = keypair.to_protobuf_encoding().map_err(invalid_data)?;
+
+    // Left over from a crash, possibly with other permissions, which
+    // opening it would keep.
+    let tmp = path.with_extension("tmp");
+    match fs::remove_file(&tmp) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+
+    let mut file = options.open(&tmp)?;
+    file.write_all(&bytes)?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)?;
+    // Directories cannot be opened, let alone synced, on Windows.
+    #[cfg(unix)]
+    fs::File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+#[cfg(unix)]
+fn check_permissions(path: &Path) -> io::Result<()> {
+    let mode = fs::metadata(path)?.permissions().mode();
+    if mode & 0o077 != 0 {
+        eprintln!(
+            "Warning: identity key file {:?} is accessible by other users (mode {:o})",
+            path,
+            mode & 0o777
+        );
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn check_permissions(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
@@ -1,204 +1,567 @@
+mod addresses;
+mod behaviour;
+mod chunk;
+mod codec;
+mod command;
+mod crdt;
+mod display;
+mod envelope;
+mod keypair;
+mod store;
+mod tombstone;
+mod transport;
+mod validator;
+mod wal;
+mod watch;
+
+use clap::Parser;
 use libp2p::{
-    development_transport,
+    dcutr,
     futures::StreamExt,
-    identity,
-    kad::{record::Key, store::MemoryStore, Kademlia, KademliaEvent, QueryResult, Quorum, Record, PeerRecord, PutRecordOk, AddProviderOk},
-    mdns::{Mdns, MdnsEvent},
-    swarm::{NetworkBehaviourEventProcess, SwarmBuilder, SwarmEvent},
-    NetworkBehaviour, PeerId,
+    gossipsub::{self, MessageAuthenticity},
+    identify,
+    kad::{self, store::RecordStore, Mode, Quorum, StoreInserts},
+    mdns,
+    multiaddr::Protocol,
+    relay,
+    swarm::{self, SwarmEvent},
+    Multiaddr, PeerId, Swarm,
+};
+use addresses::{AddressBook, Source};
+use behaviour::{MyBehaviour, Network};
+use clap::ValueEnum;
+use crdt::{Crdt, GCounter, LwwRegister, OrSet, PnCounter};
+use display::DisplayMode;
+use std::{
+    collections::{HashMap, HashSet},
+    error::Error,
+    path::PathBuf,
+    slice::Iter,
+    time::Duration,
 };
-use std::{error::Error};
+use command::Token;
+use store::DiskStore;
+use validator::{ContentValidator, PublicKeyValidator, Validators};
 use tokio::{self};
 use async_std::io::{self, prelude::BufReadExt};
 
+/// How often expired records are removed from the local store.
+const EXPIRY_SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How long a connection without open streams is kept. Peers found through
+/// the DHT are dialed again when needed, but relay reservations need their
+/// connections to stay up in between.
+const IDLE_CONNECTION_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How many peers have to see us at an address before it is announced. One
+/// peer alone may be mistaken, e.g. behind a NAT of its own, or lying.
+const MIN_OBSERVERS: usize = 2;
+
+#[derive(Debug, Parser)]
+#[clap(name = "libp2pdb", about = "A key-value store on top of the libp2p Kademlia DHT")]
+struct Opt {
+    /// Directory holding the node's persistent state.
+    #[clap(long, env = "LIBP2PDB_DATA_DIR", default_value = "libp2pdb-data")]
+    data_dir: PathBuf,
+
+    /// File holding the node's identity keypair, created on first run.
+    /// Defaults to `identity.key` inside the data directory.
+    #[clap(long, env = "LIBP2PDB_KEY_FILE")]
+    key_file: Option<PathBuf>,
+
+    /// Address to listen on, such as `/ip4/0.0.0.0/tcp/4001` for TCP,
+    /// `/ip4/0.0.0.0/udp/4001/quic-v1` for QUIC,
+    /// `/ip4/0.0.0.0/tcp/4002/ws` for WebSocket, or
+    /// `<relay address>/p2p/<PeerId>/p2p-circuit` to be reachable through a
+    /// relay from behind NAT. May be given multiple times.
+    #[clap(
+        long = "listen",
+        env = "LIBP2PDB_LISTEN",
+        value_delimiter = ',',
+        default_value = "/ip4/0.0.0.0/tcp/0"
+    )]
+    listen_addrs: Vec<Multiaddr>,
+
+    /// PEM certificate chain for `/wss` listen addresses.
+    #[clap(long, env = "LIBP2PDB_TLS_CERT", requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM private key for `/wss` listen addresses.
+    #[clap(long, env = "LIBP2PDB_TLS_KEY", requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+
+    /// Act as a circuit relay for peers behind NAT. Needs `--announce`, as
+    /// the relay hands its announced addresses to the peers it relays for.
+    #[clap(long, env = "LIBP2PDB_RELAY", requires = "announce_addrs")]
+    relay: bool,
+
+    /// Address to advertise to other peers in addition to the listen
+    /// addresses, e.g. a public address in front of a NAT. May be given
+    /// multiple times.
+    #[clap(long = "announce", env = "LIBP2PDB_ANNOUNCE", value_delimiter = ',')]
+    announce_addrs: Vec<Multiaddr>,
+
+    /// Peer to join the DHT through, as a multiaddr ending in
+    /// `/p2p/<PeerId>`. May be given multiple times.
+    #[clap(long = "bootstrap", env = "LIBP2PDB_BOOTSTRAP", value_delimiter = ',')]
+    bootstrap_addrs: Vec<Multiaddr>,
+
+    /// Seconds between Kademlia bootstrap rounds that refresh the routing
+    /// table.
+    #[clap(
+        long,
+        env = "LIBP2PDB_BOOTSTRAP_INTERVAL",
+        default_value = "300",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    bootstrap_interval: u64,
+
+    /// How keys and values are printed. Can be changed at runtime with
+    /// `DISPLAY <mode>`.
+    #[clap(long, value_enum, default_value = "auto")]
+    display: DisplayMode,
+
+    /// Default number of peers that must answer a GET or acknowledge a PUT
+    /// or DELETE, and that a CAS reads the current value from: `one`,
+    /// `majority`, `all` or a number. Commands can override it with
+    /// `quorum=<quorum>`.
+    #[clap(
+        long,
+        env = "LIBP2PDB_QUORUM",
+        default_value = "one",
+        value_parser = command::parse_quorum
+    )]
+    quorum: Quorum,
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
-    let id_key = identity::Keypair::generate_ed25519();
+    let opt = Opt::parse();
+
+    let key_file = opt
+        .key_file
+        .clone()
+        .unwrap_or_else(|| opt.data_dir.join("identity.key"));
+    let id_key = keypair::load_or_generate(&key_file)?;
     let peer_id = PeerId::from(id_key.public());
     println!("local peer id is {:?}", peer_id);
 
-    let transport = development_transport(id_key).await?;
+    let tls = match (&opt.tls_cert, &opt.tls_key) {
+        (Some(cert), Some(key)) => Some(transport::load_tls(cert, key)?),
+        _ => None,
+    };
+    let listen_wss = tls.is_some();
+    let (relay_transport, relay_client) = relay::client::new(peer_id);
+    let transport = transport::build(&id_key, tls, relay_transport)?;
 
-    #[derive(NetworkBehaviour)]
-    #[behaviour(event_process = true)]
-    struct MyBehaviour {
-        kademlia: Kademlia<MemoryStore>,
-        mdns: Mdns,
-    }
+    let mut swarm = {
+        let store = DiskStore::open(&opt.data_dir, peer_id)?;
+        // Inbound records go through `MyBehaviour::accept_record` so tombstones
+        // can be checked before they reach the store.
+        let mut config = kad::Config::default();
+        config.set_record_filtering(StoreInserts::FilterBoth);
+        let mut kademlia = kad::Behaviour::with_config(peer_id, store, config);
+        // Serve the DHT even before any address is confirmed as reachable,
+        // as peers on the LAN or behind the same relay can still use it.
+        kademlia.set_mode(Some(Mode::Server));
+        let mdns = mdns::tokio::Behaviour::new(Default::default(), peer_id)?;
+        let mut gossipsub = gossipsub::Behaviour::new(
+            MessageAuthenticity::Signed(id_key.clone()),
+            gossipsub::Config::default(),
+        )?;
+        // Every node relays changes, watching or not; see `watch`.
+        gossipsub.subscribe(&watch::topic())?;
+        let identify = identify::Behaviour::new(
+            identify::Config::new("/libp2pdb/1.0.0".to_string(), id_key.public())
+                .with_agent_version(format!("libp2pdb/{}", env!("CARGO_PKG_VERSION"))),
+        );
+        let relay = opt
+            .relay
+            .then(|| relay::Behaviour::new(peer_id, Default::default()))
+            .into();
+        let mut validators = Validators::new();
+        validators.register("/pk/", PublicKeyValidator);
+        validators.register("/cas/", ContentValidator);
+        let network = Network {
+            kademlia,
+            mdns,
+            gossipsub,
+            identify,
+            relay,
+            relay_client,
+            dcutr: dcutr::Behaviour::new(peer_id),
+        };
+        let behaviour = MyBehaviour {
+            network,
+            display: opt.display,
+            keypair: id_key,
+            pending: HashMap::new(),
+            lookups: HashMap::new(),
+            addresses: AddressBook::default(),
+            downloads: HashMap::new(),
+            validators,
+            quorum: opt.quorum,
+            watched: HashSet::new(),
+            observed: HashMap::new(),
+        };
 
-    impl NetworkBehaviourEventProcess<MdnsEvent> for MyBehaviour {
-        fn inject_event(&mut self, event: MdnsEvent) {
-            if let MdnsEvent::Discovered(list) = event {
-                for (peer_id, multiaddrr) in list {
-                    self.kademlia.add_address(&peer_id, multiaddrr);
-                }
-            }
+        let config = swarm::Config::with_tokio_executor()
+            .with_idle_connection_timeout(IDLE_CONNECTION_TIMEOUT);
+        Swarm::new(transport, behaviour, peer_id, config)
+    };
+
+     let mut stdin = io::BufReader::new(io::stdin()).lines().fuse();
+
+    for addr in opt.listen_addrs {
+        transport::check_supported(&addr)?;
+        if transport::is_wss(&addr) && !listen_wss {
+            return Err(format!("listening on {} needs --tls-cert and --tls-key", addr).into());
         }
+        swarm.listen_on(addr)?;
+    }
+    for addr in opt.announce_addrs {
+        println!("Announcing {:?}", addr);
+        swarm.add_external_address(addr);
     }
 
-    impl NetworkBehaviourEventProcess<KademliaEvent> for MyBehaviour {
-        fn inject_event(&mut self, message: KademliaEvent) {
-            match message {
-                KademliaEvent::OutboundQueryCompleted { result, .. } => match result {
-                    QueryResult::GetProviders(Ok(ok)) => {
-                        for peer in ok.providers {
-                            println!(
-                                "Peer {:?} provides key {:?}",
-                                peer,
-                                std::str::from_utf8(ok.key.as_ref()).unwrap()
-                            );
-                        }
-                    }
-                    QueryResult::GetProviders(Err(err)) => {
-                        eprintln!("Failed to get providers: {:?}", err);
-                    }
-                    QueryResult::GetRecord(Ok(ok)) => {
-                        for PeerRecord {
-                            record: Record { key, value, .. },
-                            ..
-                        } in ok.records
-                        {
-                            println!(
-                                "Got record {:?} {:?}",
-                                std::str::from_utf8(key.as_ref()).unwrap(),
-                                std::str::from_utf8(&value).unwrap(),
-                            );
-                        }
-                    }
-                    QueryResult::GetRecord(Err(err)) => {
-                        eprintln!("Failed to get record: {:?}", err);
-                    }
-                    QueryResult::PutRecord(Ok(PutRecordOk { key })) => {
-                        println!(
-                            "Successfully put record {:?}",
-                            std::str::from_utf8(key.as_ref()).unwrap()
-                        );
-                    }
-                    QueryResult::PutRecord(Err(err)) => {
-                        eprintln!("Failed to put record: {:?}", err);
-                    }
-                    QueryResult::StartProviding(Ok(AddProviderOk { key })) => {
-                        println!(
-                            "Successfully put provider record {:?}",
-                            std::str::from_utf8(key.as_ref()).unwrap()
-                        );
-                    }
-                    QueryResult::StartProviding(Err(err)) => {
-                        eprintln!("Failed to put provider record: {:?}", err);
-                    }
-                    _ => {}
-                },
-                _ => {}
+    for addr in opt.bootstrap_addrs {
+        if let Err(err) = transport::check_supported(&addr) {
+            eprintln!("Skipping bootstrap address: {}", err);
+            continue;
+        }
+        match split_peer_id(addr.clone()) {
+            Some((peer, addr)) => {
+                swarm
+                    .behaviour_mut()
+                    .add_address(peer, addr, Source::Bootstrap);
             }
+            None => eprintln!("Bootstrap address {:?} does not end in /p2p/<PeerId>", addr),
         }
     }
 
-    let mut swarm = {
-        let store = MemoryStore::new(peer_id);
-        let kademlia = Kademlia::new(peer_id, store);
-        let mdns = Mdns::new(Default::default()).await?;
-        let behaviour = MyBehaviour { kademlia, mdns };
-
-        SwarmBuilder::new(transport, behaviour, peer_id)
-            .executor(Box::new(|fut| {
-                tokio::spawn(fut);
-            }))
-            .build()
-    };
-
-     let mut stdin = io::BufReader::new(io::stdin()).lines().fuse();
-
-    // Listen on all interfaces and whatever port the OS assigns.
-    swarm.listen_on("/ip4/0.0.0.0/tcp/0".parse()?)?;
+    // The first tick fires immediately, bootstrapping right away.
+    let mut bootstrap = tokio::time::interval(Duration::from_secs(opt.bootstrap_interval));
+    let mut sweep = tokio::time::interval(EXPIRY_SWEEP_INTERVAL);
 
     // Kick it off.
     loop {
         tokio::select! {
-            line = stdin.select_next_some() => handle_input_line(&mut swarm.behaviour_mut().kademlia, line.expect("Stdin not to close")),
+            line = stdin.select_next_some() => handle_input_line(&mut swarm, line.expect("Stdin not to close")),
+            _ = bootstrap.tick() => {
+                // Fails only while the routing table is empty, e.g. before
+                // mDNS has found anyone; the next tick will try again.
+                let _ = swarm.behaviour_mut().network.kademlia.bootstrap();
+            }
+            _ = sweep.tick() => swarm.behaviour_mut().network.kademlia.store_mut().remove_expired(),
             event = swarm.select_next_some() => match event {
                 SwarmEvent::NewListenAddr { address, .. } => {
                     println!("Listening in {:?}", address);
-                },
+                }
+                SwarmEvent::ConnectionEstablished { peer_id, endpoint, .. } => {
+                    println!("Connected to {:?} via {:?}", peer_id, endpoint.get_remote_address());
+                }
+                SwarmEvent::OutgoingConnectionError { peer_id, error, .. } => {
+                    eprintln!("Failed to dial {:?}: {}", peer_id, error);
+                }
+                SwarmEvent::NewExternalAddrCandidate { address } => {
+                    let known = swarm.external_addresses().any(|addr| *addr == address);
+                    if !known && swarm.behaviour().observers(&address) >= MIN_OBSERVERS {
+                        println!("Observed at {:?}", address);
+                        swarm.add_external_address(address);
+                    }
+                }
+                // Among others, a relay refusing or dropping a reservation.
+                SwarmEvent::ListenerClosed { addresses, reason: Err(err), .. } => {
+                    eprintln!("Stopped listening on {:?}: {}", addresses, err);
+                }
                 _ => {}
             }
         }
     }
 }
 
-fn handle_input_line(kademlia: &mut Kademlia<MemoryStore>, line: String) {
-    let mut args = line.split(' ');
+/// Splits a trailing `/p2p/<PeerId>` off `addr`.
+fn split_peer_id(mut addr: Multiaddr) -> Option<(PeerId, Multiaddr)> {
+    match addr.pop()? {
+        Protocol::P2p(peer) => Some((peer, addr)),
+        _ => None,
+    }
+}
 
-    match args.next() {
+fn handle_input_line(swarm: &mut Swarm<MyBehaviour>, line: String) {
+    let args = match command::tokenize(&line) {
+        Ok(args) => args,
+        Err(err) => {
+            eprintln!("Invalid input: {}", err);
+            return;
+        }
+    };
+    if let Err(err) = run_command(swarm, &mut args.iter()) {
+        eprintln!("{}", err);
+    }
+}
+
+fn run_command(swarm: &mut Swarm<MyBehaviour>, args: &mut Iter<Token>) -> Result<(), String> {
+    let default_quorum = swarm.behaviour().quorum;
+    let display = swarm.behaviour().display;
+    let local = *swarm.local_peer_id();
+
+    match args.next().and_then(|cmd| cmd.as_str()) {
         Some("GET") => {
-            let key = {
-                match args.next() {
-                    Some(key) => Key::new(&key),
-                    None => {
-                        println!("Expected Key");
-                        return;
-                    }
-                }
-            };
-            kademlia.get_record(key, Quorum::One);
+            let key = command::key_arg(args)?;
+            let options = command::options(args, &["quorum"]).map_err(invalid_option)?;
+            let quorum = command::quorum_option(&options, default_quorum)?;
+            swarm.behaviour_mut().get_record(key, quorum);
         }
         Some("GET_PROVIDER") => {
-            let key = {
-                match args.next() {
-                    Some(key) => Key::new(&key),
-                    None => {
-                        println!("Expected Key");
-                        return;
-                    }
-                }
-            };
-            kademlia.get_providers(key);
+            let key = command::key_arg(args)?;
+            swarm.behaviour_mut().network.kademlia.get_providers(key);
         }
         Some("PUT") => {
-            let key = {
-                match args.next() {
-                    Some(key) => Key::new(&key),
-                    None => {
-                        println!("Expected Key");
-                        return;
+            let key = command::key_arg(args)?;
+            let value = command::value_arg(args)?;
+            let options =
+                command::options(args, &["ttl", "quorum", "seq"]).map_err(invalid_option)?;
+            let expires = command::ttl_option(&options)?;
+            let quorum = command::quorum_option(&options, default_quorum)?;
+            // `seq=next` versions the record one past what we hold locally.
+            let seq = match options.get("seq").map(String::as_str) {
+                Some("next") => Some(swarm.behaviour_mut().local_seq(&key).unwrap_or(0) + 1),
+                Some(seq) => match seq.parse::<u64>() {
+                    Ok(seq) if seq > 0 => Some(seq),
+                    _ => return Err("Invalid seq: expected next or a positive number".into()),
+                },
+                None => None,
+            };
+            swarm.behaviour_mut().put(key, &value, seq, expires, quorum);
+        }
+        Some("PUT_CAS") => {
+            let value = command::value_arg(args)?;
+            let options = command::options(args, &["ttl", "quorum"]).map_err(invalid_option)?;
+            let expires = command::ttl_option(&options)?;
+            let quorum = command::quorum_option(&options, default_quorum)?;
+            let key = validator::content_key(&value);
+            println!("Storing value under key {}", display.render(key.as_ref()));
+            swarm.behaviour_mut().put(key, &value, None, expires, quorum);
+        }
+        Some("CAS") => {
+            let key = command::key_arg(args)?;
+            // The SHA-256 printed by GET, or `none` if the key must not
+            // hold a value yet.
+            let expected = match args.next().map(|hash| hash.as_str().unwrap_or_default()) {
+                Some("none") => None,
+                Some(hash) => match hex::decode(hash) {
+                    Ok(hash) if hash.len() == 32 => Some(hash),
+                    _ => return Err("Invalid value hash: expected 64 hex digits or none".into()),
+                },
+                None => return Err("Expected value hash".into()),
+            };
+            let value = command::value_arg(args)?;
+            let options = command::options(args, &["ttl", "quorum"]).map_err(invalid_option)?;
+            let expires = command::ttl_option(&options)?;
+            let quorum = command::quorum_option(&options, default_quorum)?;
+            swarm
+                .behaviour_mut()
+                .compare_and_swap(key, expected, value, expires, quorum);
+        }
+        Some("INCR") => {
+            let key = command::key_arg(args)?;
+            let options =
+                command::options(args, &["by", "type", "quorum"]).map_err(invalid_option)?;
+            let by = by_option(&options)?;
+            // The type only matters when the key does not hold a counter yet.
+            let new = match options.get("type").map(String::as_str) {
+                Some("g-counter") => Crdt::GCounter(GCounter::default()),
+                Some("pn-counter") | None => Crdt::PnCounter(PnCounter::default()),
+                Some(_) => return Err("Invalid type: expected g-counter or pn-counter".into()),
+            };
+            let quorum = command::quorum_option(&options, default_quorum)?;
+            swarm
+                .behaviour_mut()
+                .update_crdt(key, quorum, |current| match current.unwrap_or(new) {
+                    Crdt::GCounter(mut counter) => {
+                        counter.increment(local, by);
+                        Ok(Crdt::GCounter(counter))
+                    }
+                    Crdt::PnCounter(mut counter) => {
+                        counter.increment(local, by);
+                        Ok(Crdt::PnCounter(counter))
+                    }
+                    other => Err(format!("Cannot INCR a {}", other.type_name())),
+                });
+        }
+        Some("DECR") => {
+            let key = command::key_arg(args)?;
+            let options = command::options(args, &["by", "quorum"]).map_err(invalid_option)?;
+            let by = by_option(&options)?;
+            let quorum = command::quorum_option(&options, default_quorum)?;
+            swarm.behaviour_mut().update_crdt(key, quorum, |current| {
+                match current.unwrap_or(Crdt::PnCounter(PnCounter::default())) {
+                    Crdt::PnCounter(mut counter) => {
+                        counter.decrement(local, by);
+                        Ok(Crdt::PnCounter(counter))
                     }
+                    other => Err(format!("Cannot DECR a {}", other.type_name())),
                 }
-            };
-            let value = {
-                match args.next() {
-                    Some(value) => value.as_bytes().to_vec(),
+            });
+        }
+        Some("SADD") => {
+            let key = command::key_arg(args)?;
+            let member = command::bytes_arg(args, "member")?;
+            let options = command::options(args, &["quorum"]).map_err(invalid_option)?;
+            let quorum = command::quorum_option(&options, default_quorum)?;
+            swarm
+                .behaviour_mut()
+                .update_crdt(key, quorum, |current| match current {
+                    Some(Crdt::OrSet(mut set)) => {
+                        set.add(local, member);
+                        Ok(Crdt::OrSet(set))
+                    }
                     None => {
-                        eprintln!("Expected value");
-                        return;
+                        let mut set = OrSet::default();
+                        set.add(local, member);
+                        Ok(Crdt::OrSet(set))
                     }
-                }
-            };
-            let record = Record {
-                key,
-                value,
-                publisher: None,
-                expires: None,
-            };
-            kademlia
-                .put_record(record, Quorum::One)
-                .expect("Failed to store record locally.");
+                    Some(other) => Err(format!("Cannot SADD to a {}", other.type_name())),
+                });
         }
-        Some("PUT_PROVIDER") => {
-            let key = {
-                match args.next() {
-                    Some(key) => Key::new(&key),
+        Some("SREM") => {
+            let key = command::key_arg(args)?;
+            let member = command::bytes_arg(args, "member")?;
+            let options = command::options(args, &["quorum"]).map_err(invalid_option)?;
+            let quorum = command::quorum_option(&options, default_quorum)?;
+            swarm
+                .behaviour_mut()
+                .update_crdt(key, quorum, |current| match current {
+                    Some(Crdt::OrSet(mut set)) => match set.remove(&member) {
+                        true => Ok(Crdt::OrSet(set)),
+                        false => Err(format!("{} is not a member", display.render(&member))),
+                    },
+                    None => Err(format!("{} is not a member", display.render(&member))),
+                    Some(other) => Err(format!("Cannot SREM from a {}", other.type_name())),
+                });
+        }
+        Some("LWW_SET") => {
+            let key = command::key_arg(args)?;
+            let value = command::value_arg(args)?;
+            let options = command::options(args, &["quorum"]).map_err(invalid_option)?;
+            let quorum = command::quorum_option(&options, default_quorum)?;
+            swarm
+                .behaviour_mut()
+                .update_crdt(key, quorum, |current| match current {
+                    Some(Crdt::LwwRegister(mut register)) => {
+                        register.set(local, value);
+                        Ok(Crdt::LwwRegister(register))
+                    }
                     None => {
-                        eprintln!("Expected key");
-                        return;
+                        let mut register = LwwRegister::default();
+                        register.set(local, value);
+                        Ok(Crdt::LwwRegister(register))
                     }
-                }
+                    Some(other) => Err(format!("Cannot LWW_SET a {}", other.type_name())),
+                });
+        }
+        Some("PUT_PROVIDER") => {
+            let key = command::key_arg(args)?;
+            let kademlia = &mut swarm.behaviour_mut().network.kademlia;
+            if let Err(err) = kademlia.start_providing(key.clone()) {
+                eprintln!(
+                    "Failed to start providing key {}: {:?}",
+                    display.render(key.as_ref()),
+                    err
+                );
+            }
+        }
+        Some("DIAL") => {
+            let addr = match args.next().and_then(|addr| addr.as_str()) {
+                Some(addr) => addr
+                    .parse::<Multiaddr>()
+                    .map_err(|e| format!("Invalid multiaddr: {}", e))?,
+                None => return Err("Expected multiaddr".into()),
             };
-
-            kademlia
-                .start_providing(key)
-                .expect("Failed to start providing key");
+            transport::check_supported(&addr).map_err(|e| format!("Cannot dial: {}", e))?;
+            if let Some((peer, peer_addr)) = split_peer_id(addr.clone()) {
+                swarm
+                    .behaviour_mut()
+                    .add_address(peer, peer_addr, Source::Manual);
+            }
+            if let Err(err) = swarm.dial(addr) {
+                eprintln!("Failed to dial: {}", err);
+            }
+        }
+        Some("STOP_PROVIDING") => {
+            let key = command::key_arg(args)?;
+            let kademlia = &mut swarm.behaviour_mut().network.kademlia;
+            if !kademlia.store_mut().provided().any(|r| r.key == key) {
+                return Err(format!("Not providing key {}", display.render(key.as_ref())));
+            }
+            // Other peers keep listing us until their copies of the provider
+            // record expire.
+            kademlia.stop_providing(&key);
+            println!("Stopped providing key {}", display.render(key.as_ref()));
+        }
+        Some("LIST_PROVIDING") => {
+            let kademlia = &mut swarm.behaviour_mut().network.kademlia;
+            for record in kademlia.store_mut().provided() {
+                println!("Providing key {}", display.render(record.key.as_ref()));
+            }
+        }
+        Some("DELETE") => {
+            let key = command::key_arg(args)?;
+            let options = command::options(args, &["quorum"]).map_err(invalid_option)?;
+            let quorum = command::quorum_option(&options, default_quorum)?;
+            swarm.behaviour_mut().delete(key, quorum);
+        }
+        Some("WATCH") => {
+            let key = command::key_arg(args)?;
+            if swarm.behaviour_mut().watched.insert(key.clone()) {
+                println!("Watching key {}", display.render(key.as_ref()));
+            } else {
+                eprintln!("Already watching key {}", display.render(key.as_ref()));
+            }
+        }
+        Some("UNWATCH") => {
+            let key = command::key_arg(args)?;
+            if swarm.behaviour_mut().watched.remove(&key) {
+                println!("Stopped watching key {}", display.render(key.as_ref()));
+            } else {
+                eprintln!("Not watching key {}", display.render(key.as_ref()));
+            }
+        }
+        Some("PEERS") => {
+            for (peer, addr, sources) in swarm.behaviour().addresses.iter() {
+                let sources: Vec<String> = sources.iter().map(ToString::to_string).collect();
+                println!("Peer {:?} at {:?} from {}", peer, addr, sources.join(", "));
+            }
+        }
+        Some("DISPLAY") => {
+            let mode = match args.next().and_then(|mode| mode.as_str()) {
+                Some(mode) => DisplayMode::from_str(mode, true)
+                    .map_err(|e| format!("Invalid display mode: {}", e))?,
+                None => return Err("Expected auto, hex or base64".into()),
+            };
+            swarm.behaviour_mut().display = mode;
         }
         _ => {
-            println!("Expected GET, GET_PROVIDER, PUT, PUT_PROVIDER");
+            println!(
+                "Expected GET, GET_PROVIDER, PUT, PUT_CAS, CAS, INCR, DECR, SADD, SREM, \
+                 LWW_SET, PUT_PROVIDER, STOP_PROVIDING, LIST_PROVIDING, DELETE, WATCH, \
+                 UNWATCH, DIAL, PEERS, DISPLAY"
+            );
         }
     }
+    Ok(())
+}
+
+fn invalid_option(err: String) -> String {
+    format!("Invalid option: {}", err)
+}
+
+/// The `by=` option of `INCR` and `DECR`.
+fn by_option(options: &HashMap<String, String>) -> Result<u64, String> {
+    match options.get("by").map(|by| by.parse::<u64>()) {
+        Some(Ok(by)) if by > 0 => Ok(by),
+        Some(_) => Err("Invalid by: expected a positive number".into()),
+        None => Ok(1),
+    }
 }
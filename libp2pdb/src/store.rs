@@ -0,0 +1,503 @@
+//! A disk-backed Kademlia `RecordStore`.
+//!
+//! Records and provider records are kept in a `MemoryStore` for lookups.
+//! Every mutation is appended to a write-ahead log and synced before the
+//! store call returns. The log is periodically folded into a snapshot file, and
+//! on startup the snapshot is loaded and the log replayed on top of it, so a
+//! restarted node serves what it held before, even after a crash.
+
+use crate::{
+    codec::{invalid_data, Reader, Writer},
+    wal::Wal,
+};
+use libp2p::{
+    kad::{
+        store::{self, MemoryStore, RecordStore},
+        ProviderRecord, Record, RecordKey as Key,
+    },
+    Multiaddr, PeerId,
+};
+use std::{
+    borrow::Cow,
+    collections::HashSet,
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+const SNAPSHOT_FILE: &str = "store.snapshot";
+const WAL_FILE: &str = "store.wal";
+const SNAPSHOT_MAGIC: &[u8; 4] = b"L2DB";
+const SNAPSHOT_VERSION: u8 = 1;
+
+/// Number of log entries after which they are folded into the snapshot.
+const CHECKPOINT_INTERVAL: usize = 1024;
+
+// Snapshot entries use the same encoding as the corresponding log entries.
+const TAG_RECORD: u8 = 1;
+const TAG_PROVIDER: u8 = 2;
+const TAG_REMOVE: u8 = 3;
+const TAG_REMOVE_PROVIDER: u8 = 4;
+
+pub struct DiskStore {
+    dir: PathBuf,
+    wal: Wal,
+    inner: MemoryStore,
+    /// Keys with at least one provider record. `MemoryStore` only exposes
+    /// provider records per key, so this is what lets us enumerate them.
+    provider_keys: HashSet<Key>,
+}
+
+impl DiskStore {
+    /// Opens the store in `dir`, creating the directory if needed and
+    /// loading any records persisted by a previous run.
+    pub fn open(dir: impl AsRef<Path>, local_id: PeerId) -> io::Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+
+        let (wal, entries) = Wal::open(dir.join(WAL_FILE))?;
+        let mut store = DiskStore {
+            dir,
+            wal,
+            inner: MemoryStore::new(local_id),
+            provider_keys: HashSet::new(),
+        };
+
+        let path = store.dir.join(SNAPSHOT_FILE);
+        if path.exists() {
+            let data = fs::read(&path)?;
+            store.load_snapshot(&data)?;
+        }
+        for entry in &entries {
+            store.apply(&mut Reader::new(entry))?;
+        }
+        if !entries.is_empty() {
+            store.checkpoint()?;
+        }
+
+        Ok(store)
+    }
+
+    fn load_snapshot(&mut self, data: &[u8]) -> io::Result<()> {
+        let mut r = Reader::new(data);
+        if r.u32()?.to_be_bytes() != *SNAPSHOT_MAGIC {
+            return Err(invalid_data("not a record store snapshot"));
+        }
+        let version = r.u8()?;
+        if version != SNAPSHOT_VERSION {
+            return Err(invalid_data(format!(
+                "unsupported snapshot version {}",
+                version
+            )));
+        }
+
+        while !r.is_empty() {
+            self.apply(&mut r)?;
+        }
+
+        Ok(())
+    }
+
+    /// Applies one encoded mutation to the in-memory store. Entries are
+    /// logged before the in-memory store sees them, so the log may hold
+    /// records it rejected; replay rejects them again and moves on.
+    fn apply(&mut self, r: &mut Reader) -> io::Result<()> {
+        let now = Instant::now();
+        match r.u8()? {
+            TAG_RECORD => {
+                let record = decode_record(r)?;
+                if !record.is_expired(now) {
+                    let _ = self.inner.put(record);
+                }
+            }
+            TAG_PROVIDER => {
+                let record = decode_provider(r)?;
+                if !record.is_expired(now) {
+                    let key = record.key.clone();
+                    if self.inner.add_provider(record).is_ok() {
+                        self.provider_keys.insert(key);
+                    }
+                }
+            }
+            TAG_REMOVE => {
+                let key = Key::from(r.bytes()?.to_vec());
+                self.inner.remove(&key);
+            }
+            TAG_REMOVE_PROVIDER => {
+                let key = Key::from(r.bytes()?.to_vec());
+                let provider = PeerId::from_bytes(r.bytes()?).map_err(invalid_data)?;
+                self.inner.remove_provider(&key, &provider);
+                if self.inner.providers(&key).is_empty() {
+                    self.provider_keys.remove(&key);
+                }
+            }
+            tag => return Err(invalid_data(format!("unknown store entry {}", tag))),
+        }
+        Ok(())
+    }
+
+    fn snapshot(&self) -> Vec<u8> {
+        let now = Instant::now();
+        let mut w = Writer::new();
+        w.u32(u32::from_be_bytes(*SNAPSHOT_MAGIC))
+            .u8(SNAPSHOT_VERSION);
+
+        for record in self.inner.records() {
+            if !record.is_expired(now) {
+                w.u8(TAG_RECORD);
+                encode_record(&mut w, &record);
+            }
+        }
+        for key in &self.provider_keys {
+            for record in self.inner.providers(key) {
+                if !record.is_expired(now) {
+                    w.u8(TAG_PROVIDER);
+                    encode_provider(&mut w, &record);
+                }
+            }
+        }
+
+        w.into_inner()
+    }
+
+    /// Atomically replaces the snapshot file with the current contents of
+    /// the store.
+    fn save(&self) -> io::Result<()> {
+        let path = self.dir.join(SNAPSHOT_FILE);
+        let tmp = path.with_extension("tmp");
+
+        let mut file = File::create(&tmp)?;
+        file.write_all(&self.snapshot())?;
+        file.sync_all()?;
+        fs::rename(&tmp, &path)?;
+        // Directories cannot be opened, let alone synced, on Windows.
+        #[cfg(unix)]
+        File::open(&self.dir)?.sync_all()?;
+
+        Ok(())
+    }
+
+    /// Writes a snapshot and empties the log it now covers. A crash in
+    /// between is harmless since replaying the log over the new snapshot is
+    /// idempotent.
+    fn checkpoint(&mut self) -> io::Result<()> {
+        self.save()?;
+        self.wal.reset()
+    }
+
+    /// Makes `entry` durable, folding the log into the snapshot once it has
+    /// grown long enough. Mutations are only applied once this succeeds, so
+    /// the store never serves anything a restart would lose.
+    fn log(&mut self, entry: Writer) -> io::Result<()> {
+        if let Err(e) = self.wal.append(&entry.into_inner()) {
+            eprintln!("Failed to write record store log: {:?}", e);
+            return Err(e);
+        }
+        // The entry is durable either way; a failed checkpoint is retried
+        // with the next one.
+        if self.wal.len() >= CHECKPOINT_INTERVAL {
+            if let Err(e) = self.checkpoint() {
+                eprintln!("Failed to checkpoint record store: {:?}", e);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl DiskStore {
+    /// Removes expired records and provider records. Kademlia only drops an
+    /// expired record when it is looked up, so without this they would sit
+    /// in the store, and be persisted, indefinitely.
+    pub fn remove_expired(&mut self) {
+        let now = Instant::now();
+        let records: Vec<Key> = self
+            .inner
+            .records()
+            .filter(|r| r.is_expired(now))
+            .map(|r| r.key.clone())
+            .collect();
+        for key in records {
+            self.remove(&key);
+        }
+
+        let providers: Vec<(Key, PeerId)> = self
+            .provider_keys
+            .iter()
+            .flat_map(|key| self.inner.providers(key))
+            .filter(|r| r.is_expired(now))
+            .map(|r| (r.key, r.provider))
+            .collect();
+        for (key, provider) in providers {
+            self.remove_provider(&key, &provider);
+        }
+    }
+}
+
+impl RecordStore for DiskStore {
+    type RecordsIter<'a> = <MemoryStore as RecordStore>::RecordsIter<'a>;
+    type ProvidedIter<'a> = <MemoryStore as RecordStore>::ProvidedIter<'a>;
+
+    fn get(&self, k: &Key) -> Option<Cow<'_, Record>> {
+        self.inner.get(k)
+    }
+
+    fn put(&mut self, r: Record) -> store::Result<()> {
+        let mut entry = Writer::new();
+        entry.u8(TAG_RECORD);
+        encode_record(&mut entry, &r);
+
+        // `store::Error` has no variant for I/O errors; a store that cannot
+        // log is as good as full.
+        self.log(entry).map_err(|_| store::Error::MaxRecords)?;
+        self.inner.put(r)
+    }
+
+    fn remove(&mut self, k: &Key) {
+        let mut entry = Writer::new();
+        entry.u8(TAG_REMOVE).bytes(k.as_ref());
+
+        if self.log(entry).is_ok() {
+            self.inner.remove(k);
+        }
+    }
+
+    fn records(&self) -> Self::RecordsIter<'_> {
+        self.inner.records()
+    }
+
+    fn add_provider(&mut self, record: ProviderRecord) -> store::Result<()> {
+        let mut entry = Writer::new();
+        entry.u8(TAG_PROVIDER);
+        encode_provider(&mut entry, &record);
+
+        self.log(entry).map_err(|_| store::Error::MaxProvidedKeys)?;
+        let key = record.key.clone();
+        self.inner.add_provider(record)?;
+        self.provider_keys.insert(key);
+        Ok(())
+    }
+
+    fn providers(&self, key: &Key) -> Vec<ProviderRecord> {
+        self.inner.providers(key)
+    }
+
+    fn provided(&self) -> Self::ProvidedIter<'_> {
+        self.inner.provided()
+    }
+
+    fn remove_provider(&mut self, k: &Key, p: &PeerId) {
+        let mut entry = Writer::new();
+        entry
+            .u8(TAG_REMOVE_PROVIDER)
+            .bytes(k.as_ref())
+            .bytes(&p.to_bytes());
+
+        if self.log(entry).is_err() {
+            return;
+        }
+        self.inner.remove_provider(k, p);
+        if self.inner.providers(k).is_empty() {
+            self.provider_keys.remove(k);
+        }
+    }
+}
+
+fn encode_record(w: &mut Writer, record: &Record) {
+    w.bytes(record.key.as_ref())
+        .bytes(&record.value)
+        .bytes(&record.publisher.map(|p| p.to_bytes()).unwrap_or_default())
+        .u64(record.expires.map(instant_to_unix_ms).unwrap_or(0));
+}
+
+fn decode_record(r: &mut Reader) -> io::Result<Record> {
+    let key = Key::from(r.bytes()?.to_vec());
+    let value = r.bytes()?.to_vec();
+    let publisher = match r.bytes()? {
+        [] => None,
+        bytes => Some(PeerId::from_bytes(bytes).map_err(invalid_data)?),
+    };
+    let expires = unix_ms_to_instant(r.u64()?);
+
+    Ok(Record {
+        key,
+        value,
+        publisher,
+        expires,
+    })
+}
+
+fn encode_provider(w: &mut Writer, record: &ProviderRecord) {
+    w.bytes(record.key.as_ref())
+        .bytes(&record.provider.to_bytes())
+        .u64(record.expires.map(instant_to_unix_ms).unwrap_or(0))
+        .u32(record.addresses.len() as u32);
+    for addr in &record.addresses {
+        w.bytes(&addr.to_vec());
+    }
+}
+
+fn decode_provider(r: &mut Reader) -> io::Result<ProviderRecord> {
+    let key = Key::from(r.bytes()?.to_vec());
+    let provider = PeerId::from_bytes(r.bytes()?).map_err(invalid_data)?;
+    let expires = unix_ms_to_instant(r.u64()?);
+    let mut addresses = Vec::new();
+    for _ in 0..r.u32()? {
+        addresses.push(Multiaddr::try_from(r.bytes()?.to_vec()).map_err(invalid_data)?);
+    }
+
+    Ok(ProviderRecord {
+        key,
+        provider,
+        expires,
+        addresses,
+    })
+}
+
+/// `Instant`s are only meaningful within one process, so expiry is persisted
+/// as wall-clock milliseconds since the epoch. Zero means "never expires".
+fn instant_to_unix_ms(t: Instant) -> u64 {
+    let now = Instant::now();
+    let wall = if t >= now {
+        SystemTime::now() + (t - now)
+    } else {
+        SystemTime::now() - (now - t)
+    };
+    let ms = wall
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0);
+    ms.max(1)
+}
+
+fn unix_ms_to_instant(ms: u64) -> Option<Instant> {
+    if ms == 0 {
+        return None;
+    }
+    let wall = UNIX_EPOCH + Duration::from_millis(ms);
+    let now = Instant::now();
+    Some(match wall.duration_since(SystemTime::now()) {
+        Ok(remaining) => now + remaining,
+        Err(e) => now.checked_sub(e.duration()).unwrap_or(now),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A fresh store directory under the system temp directory.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("libp2pdb-store-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn record(key: &[u8], value: &[u8], expires: Option<Instant>) -> Record {
+        Record {
+            key: Key::new(&key),
+            value: value.to_vec(),
+            publisher: Some(PeerId::random()),
+            expires,
+        }
+    }
+
+    fn provider(key: &[u8], provider: PeerId) -> ProviderRecord {
+        let addr: Multiaddr = "/ip4/127.0.0.1/tcp/4001".parse().unwrap();
+        let mut record = ProviderRecord::new(Key::new(&key), provider, vec![addr]);
+        record.expires = Some(Instant::now() + Duration::from_secs(3600));
+        record
+    }
+
+    #[test]
+    fn reopen_restores_records_and_providers() {
+        let dir = temp_dir("restore");
+        let local = PeerId::random();
+        let remote = PeerId::random();
+        let stored = record(b"key", b"value", None);
+        {
+            let mut store = DiskStore::open(&dir, local).unwrap();
+            store.put(stored.clone()).unwrap();
+            store.add_provider(provider(b"mine", local)).unwrap();
+            store.add_provider(provider(b"theirs", remote)).unwrap();
+        }
+
+        let store = DiskStore::open(&dir, local).unwrap();
+        assert_eq!(store.get(&stored.key).unwrap().into_owned(), stored);
+        let mine = store.providers(&Key::new(b"mine"));
+        assert_eq!(mine.len(), 1);
+        assert_eq!(mine[0].provider, local);
+        assert_eq!(mine[0].addresses, provider(b"mine", local).addresses);
+        assert!(mine[0].expires.is_some());
+        assert_eq!(store.providers(&Key::new(b"theirs"))[0].provider, remote);
+        let provided: Vec<Key> = store.provided().map(|r| r.key.clone()).collect();
+        assert_eq!(provided, vec![Key::new(b"mine")]);
+    }
+
+    #[test]
+    fn removals_replay_over_snapshot() {
+        let dir = temp_dir("remove");
+        let local = PeerId::random();
+        let kept = record(b"kept", b"value", None);
+        let removed = record(b"removed", b"value", None);
+        {
+            let mut store = DiskStore::open(&dir, local).unwrap();
+            store.put(kept.clone()).unwrap();
+            store.put(removed.clone()).unwrap();
+            store.add_provider(provider(b"provided", local)).unwrap();
+            store.checkpoint().unwrap();
+            // Only in the log, on top of a snapshot still holding both.
+            store.remove(&removed.key);
+            store.remove_provider(&Key::new(b"provided"), &local);
+        }
+
+        let store = DiskStore::open(&dir, local).unwrap();
+        assert!(store.get(&kept.key).is_some());
+        assert!(store.get(&removed.key).is_none());
+        assert!(store.providers(&Key::new(b"provided")).is_empty());
+        assert_eq!(store.provided().count(), 0);
+        assert!(store.provider_keys.is_empty());
+        drop(store);
+
+        // Opening folded the log into the snapshot.
+        let store = DiskStore::open(&dir, local).unwrap();
+        assert!(store.get(&kept.key).is_some());
+        assert!(store.get(&removed.key).is_none());
+    }
+
+    #[test]
+    fn expiry_survives_reopen() {
+        let dir = temp_dir("expiry");
+        let local = PeerId::random();
+        let expires = Instant::now() + Duration::from_secs(3600);
+        let live = record(b"live", b"value", Some(expires));
+        let past = Instant::now().checked_sub(Duration::from_secs(1));
+        let expired = record(b"expired", b"value", past);
+        {
+            let mut store = DiskStore::open(&dir, local).unwrap();
+            store.put(live.clone()).unwrap();
+            store.put(expired.clone()).unwrap();
+        }
+
+        let store = DiskStore::open(&dir, local).unwrap();
+        let restored = store.get(&live.key).unwrap().expires.unwrap();
+        // Persisted in milliseconds of wall-clock time.
+        let drift = if restored > expires {
+            restored - expires
+        } else {
+            expires - restored
+        };
+        assert!(drift < Duration::from_millis(50), "drifted by {:?}", drift);
+        assert!(store.get(&expired.key).is_none());
+    }
+
+    #[test]
+    fn expiry_round_trips_through_unix_ms() {
+        assert_eq!(unix_ms_to_instant(0), None);
+        let t = Instant::now() + Duration::from_secs(60);
+        let back = unix_ms_to_instant(instant_to_unix_ms(t)).unwrap();
+        let drift = if back > t { back - t } else { t - back };
+        assert!(drift < Duration::from_millis(50), "drifted by {:?}", drift);
+    }
+}
@@ -0,0 +1,43 @@
+//! Signed tombstones marking a key as deleted.
+//!
+//! A tombstone is a signed record of kind [`Kind::Tombstone`] stored in place
+//! of the deleted value, under the same key, so it replicates like any other
+//! record. Since conflicting records are resolved in favour of the latest,
+//! peers holding a tombstone refuse to have it overwritten by the older value,
+//! which stops stale replicas from resurrecting it on the next republish.
+//!
+//! A value without a `ttl` is republished by its publisher for as long as
+//! that peer holds it, so a publisher that missed the delete, having been
+//! offline or not among the closest peers, would bring the value back once
+//! the tombstone expired. Its tombstone therefore does not expire either:
+//! the deleter keeps it and republishes it in turn, and the delete only
+//! holds while the deleter does.
+
+use crate::envelope::{self, Kind};
+use libp2p::{
+    identity::Keypair,
+    kad::{Record, RecordKey as Key},
+};
+use std::time::{Duration, Instant};
+
+/// How long the tombstone of a value with a `ttl` is kept at least. Longer
+/// than Kademlia's default record TTL of 36 hours, so replicas other peers
+/// hold expire before the tombstone does.
+pub const TTL: Duration = Duration::from_secs(48 * 60 * 60);
+
+/// Builds a tombstone record for `key`, signed with `keypair`. A versioned
+/// key needs a tombstone with a higher `seq` than the value it deletes.
+///
+/// `deleted_expires` is when the deleted record would have expired, `None`
+/// if it never does, in which case neither does the tombstone. A record
+/// stored with a `ttl` longer than [`TTL`] gets a tombstone that lives as
+/// long, or its replicas would bring it back once the tombstone is gone.
+pub fn create(
+    keypair: &Keypair,
+    key: Key,
+    seq: Option<u64>,
+    deleted_expires: Option<Instant>,
+) -> Result<Record, String> {
+    let expires = deleted_expires.map(|deleted| deleted.max(Instant::now() + TTL));
+    envelope::seal(keypair, key, Kind::Tombstone, seq, &[], expires)
+}
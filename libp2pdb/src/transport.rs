@@ -0,0 +1,215 @@
+//! The transport stack.
+//!
+//! QUIC, TCP, with DNS resolution, and WebSocket over TCP. TCP and WebSocket
+//! connections are upgraded with noise for encryption and yamux for
+//! multiplexing; QUIC brings both itself. Which of them a node listens on
+//! follows from its listen addresses: `/udp/<port>/quic-v1` for QUIC,
+//! `/tcp/<port>` for TCP, `/tcp/<port>/ws` for WebSocket, so clients that can
+//! only make HTTP connections can still reach it. Listening on
+//! `/tcp/<port>/wss` takes a TLS certificate; dialing `/wss` addresses works
+//! without one.
+//!
+//! Relayed connections go through the circuit relay v2 client transport:
+//! listening on `<relay address>/p2p/<relay>/p2p-circuit` reserves a slot on
+//! that relay, making a node behind NAT reachable at the resulting
+//! `/p2p-circuit` address until DCUtR upgrades the connection to a direct
+//! one.
+//!
+//! Only QUIC version 1 is spoken. Draft `/quic` addresses are rejected up
+//! front with an error saying so, rather than failing later as unsupported.
+
+use crate::codec::invalid_data;
+use libp2p::{
+    core::{
+        muxing::StreamMuxerBox,
+        transport::{Boxed, Transport},
+        upgrade,
+    },
+    dns,
+    futures::{future::Either, AsyncRead, AsyncWrite},
+    identity::Keypair,
+    multiaddr::Protocol,
+    noise, quic,
+    relay::client,
+    tcp,
+    websocket::{tls, WsConfig},
+    yamux, Multiaddr, PeerId,
+};
+use std::{error::Error, fs, io, path::Path, time::Duration};
+
+/// How long connection setup, including the upgrades, may take.
+const UPGRADE_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// Builds the transport. `tls` is the server configuration for `/wss`
+/// listeners, see [`load_tls`], and `relay` the transport half of the relay
+/// client behaviour.
+pub fn build(
+    keypair: &Keypair,
+    tls: Option<tls::Config>,
+    relay: client::Transport,
+) -> io::Result<Boxed<(PeerId, StreamMuxerBox)>> {
+    // Dialing from the listen port makes the address peers observe us at
+    // the one we listen on, which hole punching relies on.
+    let tcp = || tcp::tokio::Transport::new(tcp::Config::new().nodelay(true).port_reuse(true));
+    // WebSocket resolves names itself, since a `/wss` dial checks the
+    // certificate against the name rather than the address it resolves to.
+    let mut ws = WsConfig::new(dns::tokio::Transport::system(tcp())?);
+    if let Some(tls) = tls {
+        ws.set_tls_config(tls);
+    }
+    // WebSocket goes first: the DNS transport accepts any address and only
+    // fails once dialing, so it would never fall through to WebSocket.
+    let relay_ws = upgrade(relay.or_transport(ws), keypair)?;
+
+    let quic = quic::tokio::Transport::new(quic::Config::new(keypair))
+        .map(|(peer, muxer), _| (peer, StreamMuxerBox::new(muxer)))
+        .boxed();
+    let quic_tcp = dns::tokio::Transport::system(quic.or_transport(upgrade(tcp(), keypair)?))?;
+
+    Ok(relay_ws
+        .or_transport(quic_tcp)
+        .map(|output, _| match output {
+            Either::Left(output) => output,
+            Either::Right(output) => output.into_inner(),
+        })
+        .boxed())
+}
+
+/// Secures `transport` with noise and multiplexes it with yamux.
+fn upgrade<T>(transport: T, keypair: &Keypair) -> io::Result<Boxed<(PeerId, StreamMuxerBox)>>
+where
+    T: Transport + Send + Unpin + 'static,
+    T::Output: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    T::Error: Error + Send + Sync + 'static,
+    T::Dial: Send + 'static,
+    T::ListenerUpgrade: Send + 'static,
+{
+    Ok(transport
+        .upgrade(upgrade::Version::V1)
+        .authenticate(noise::Config::new(keypair).map_err(io::Error::other)?)
+        .multiplex(yamux::Config::default())
+        .timeout(UPGRADE_TIMEOUT)
+        .boxed())
+}
+
+/// Fails for addresses of transports this build cannot offer.
+pub fn check_supported(addr: &Multiaddr) -> Result<(), String> {
+    if addr.iter().any(|p| matches!(p, Protocol::Quic)) {
+        return Err(format!(
+            "{} uses draft QUIC, which is not supported; use /quic-v1",
+            addr
+        ));
+    }
+    Ok(())
+}
+
+/// Whether `addr` is a secure WebSocket address.
+pub fn is_wss(addr: &Multiaddr) -> bool {
+    addr.iter().any(|p| matches!(p, Protocol::Wss(_)))
+}
+
+/// Loads the certificate chain and private key for `/wss` listeners from PEM
+/// files.
+pub fn load_tls(cert: &Path, key: &Path) -> io::Result<tls::Config> {
+    let certs = pem_blocks(&fs::read_to_string(cert)?, "CERTIFICATE")?;
+    if certs.is_empty() {
+        return Err(invalid_data(format!("no certificate in {:?}", cert)));
+    }
+    let key = pem_blocks(&fs::read_to_string(key)?, "PRIVATE KEY")?
+        .pop()
+        .ok_or_else(|| invalid_data(format!("no private key in {:?}", key)))?;
+    tls::Config::new(
+        tls::PrivateKey::new(key),
+        certs.into_iter().map(tls::Certificate::new),
+    )
+    .map_err(io::Error::other)
+}
+
+/// Decodes the PEM blocks whose label ends in `label`, so `PRIVATE KEY` also
+/// matches `RSA PRIVATE KEY` and `EC PRIVATE KEY`.
+fn pem_blocks(pem: &str, label: &str) -> io::Result<Vec<Vec<u8>>> {
+    let mut blocks = Vec::new();
+    let mut current: Option<String> = None;
+    for line in pem.lines().map(str::trim) {
+        if let Some(begin) = line.strip_prefix("-----BEGIN ") {
+            current = begin
+                .strip_suffix("-----")
+                .filter(|l| l.ends_with(label))
+                .map(|_| String::new());
+        } else if line.starts_with("-----END ") {
+            if let Some(base64) = current.take() {
+                blocks.push(base64::decode(base64).map_err(invalid_data)?);
+            }
+        } else if let Some(base64) = &mut current {
+            base64.push_str(line);
+        }
+    }
+    Ok(blocks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pem(label: &str, base64: &str) -> String {
+        format!("-----BEGIN {0}-----\n{1}\n-----END {0}-----\n", label, base64)
+    }
+
+    /// A fresh file under the system temp directory holding `contents`.
+    fn temp_file(name: &str, contents: &str) -> std::path::PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("libp2pdb-tls-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("file.pem");
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn decodes_certificate_chains() {
+        let chain = format!("{}{}", pem("CERTIFICATE", "AQID"), pem("CERTIFICATE", "BAUG"));
+        assert_eq!(
+            pem_blocks(&chain, "CERTIFICATE").unwrap(),
+            vec![vec![1, 2, 3], vec![4, 5, 6]]
+        );
+    }
+
+    #[test]
+    fn decodes_blocks_split_over_lines() {
+        let block = "-----BEGIN CERTIFICATE-----\n  AQ\nID  \n-----END CERTIFICATE-----\n";
+        assert_eq!(pem_blocks(block, "CERTIFICATE").unwrap(), vec![vec![1, 2, 3]]);
+    }
+
+    #[test]
+    fn matches_key_labels_by_suffix() {
+        for label in ["PRIVATE KEY", "RSA PRIVATE KEY", "EC PRIVATE KEY"] {
+            let key = pem(label, "AQID");
+            assert_eq!(pem_blocks(&key, "PRIVATE KEY").unwrap(), vec![vec![1, 2, 3]]);
+        }
+        let other = format!("{}{}", pem("PUBLIC KEY", "AQID"), pem("CERTIFICATE", "AQID"));
+        assert!(pem_blocks(&other, "PRIVATE KEY").unwrap().is_empty());
+    }
+
+    #[test]
+    fn rejects_invalid_base64() {
+        assert!(pem_blocks(&pem("CERTIFICATE", "not base64!"), "CERTIFICATE").is_err());
+    }
+
+    #[test]
+    fn load_tls_needs_a_key() {
+        let cert = temp_file("cert", &pem("CERTIFICATE", "AQID"));
+        let key = temp_file("key", &pem("CERTIFICATE", "AQID"));
+        let err = load_tls(&cert, &key).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("no private key"), "{}", err);
+    }
+
+    #[test]
+    fn load_tls_needs_a_certificate() {
+        let cert = temp_file("no-cert", "");
+        let key = temp_file("no-cert-key", &pem("PRIVATE KEY", "AQID"));
+        let err = load_tls(&cert, &key).err().unwrap();
+        assert!(err.to_string().contains("no certificate"), "{}", err);
+    }
+}
@@ -0,0 +1,266 @@
+//! Per-namespace validation of records.
+//!
+//! Applications register a [`RecordValidator`] for a key prefix such as
+//! `/pk/` or `/app/`. Inbound and locally written records under that prefix
+//! are only stored if the validator accepts them, and when valid records
+//! compete for the same key the validator picks the one to keep. Keys
+//! matching no registered prefix are accepted as long as their signature
+//! verifies, and conflicts between them go to the latest record.
+
+use crate::{
+    chunk::Manifest,
+    envelope::{Envelope, Kind},
+};
+use libp2p::{
+    identity::PublicKey,
+    kad::RecordKey as Key,
+    multihash::Multihash,
+    PeerId,
+};
+use sha2::{Digest, Sha256, Sha512};
+
+/// Multicodec codes of the hash functions `/cas/` keys may use.
+const SHA2_256: u64 = 0x12;
+const SHA2_512: u64 = 0x13;
+
+pub trait RecordValidator: Send {
+    /// Decides whether a record may be stored under `key`.
+    fn validate(&self, key: &Key, record: &Envelope) -> Result<(), String>;
+
+    /// Returns the index of the best of several valid records for `key`.
+    /// Defaults to [`latest`].
+    fn select(&self, _key: &Key, records: &[&Envelope]) -> usize {
+        latest(records)
+    }
+}
+
+/// Returns the index of the record with the highest sequence number, or of
+/// the most recently created one among equal sequence numbers. Unversioned
+/// records count as sequence zero. Ties go to the earliest record, so a
+/// record never displaces an equally recent one.
+pub fn latest(records: &[&Envelope]) -> usize {
+    let order = |r: &Envelope| (r.seq.unwrap_or(0), r.created_at);
+    let mut best = 0;
+    for (i, record) in records.iter().enumerate().skip(1) {
+        if order(record) > order(records[best]) {
+            best = i;
+        }
+    }
+    best
+}
+
+/// Registered validators, matched against keys by longest prefix.
+#[derive(Default)]
+pub struct Validators {
+    namespaces: Vec<(Vec<u8>, Box<dyn RecordValidator>)>,
+}
+
+impl Validators {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers `validator` for every key starting with `prefix`, replacing
+    /// any validator previously registered for the same prefix.
+    pub fn register(
+        &mut self,
+        prefix: impl Into<Vec<u8>>,
+        validator: impl RecordValidator + 'static,
+    ) {
+        let prefix = prefix.into();
+        self.namespaces.retain(|(p, _)| *p != prefix);
+        self.namespaces.push((prefix, Box::new(validator)));
+    }
+
+    /// The validator responsible for `key`, if any.
+    pub fn get(&self, key: &Key) -> Option<&dyn RecordValidator> {
+        self.namespaces
+            .iter()
+            .filter(|(prefix, _)| key.as_ref().starts_with(prefix))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(_, v)| v.as_ref())
+    }
+
+    pub fn validate(&self, key: &Key, record: &Envelope) -> Result<(), String> {
+        match self.get(key) {
+            Some(validator) => validator.validate(key, record),
+            None => Ok(()),
+        }
+    }
+
+    /// Picks the best of several valid records for `key`.
+    pub fn select(&self, key: &Key, records: &[&Envelope]) -> usize {
+        match self.get(key) {
+            Some(validator) => validator.select(key, records),
+            None => latest(records),
+        }
+    }
+}
+
+/// Validates `/pk/<PeerId>` records, which must hold the protobuf-encoded
+/// public key of that peer and can only be written by the peer itself.
+pub struct PublicKeyValidator;
+
+impl RecordValidator for PublicKeyValidator {
+    fn validate(&self, key: &Key, record: &Envelope) -> Result<(), String> {
+        let peer = key
+            .as_ref()
+            .strip_prefix(b"/pk/")
+            .and_then(|peer| PeerId::from_bytes(peer).ok())
+            .ok_or("key is not /pk/<PeerId>")?;
+        if record.publisher != peer {
+            return Err("public key records can only be published by their peer".to_string());
+        }
+        match record.kind {
+            Kind::Value => {
+                let public = PublicKey::try_decode_protobuf(&record.payload)
+                    .map_err(|e| format!("invalid public key: {}", e))?;
+                if public.to_peer_id() != peer {
+                    return Err("public key does not match key".to_string());
+                }
+            }
+            Kind::Tombstone => {}
+            Kind::Crdt | Kind::Manifest => {
+                return Err("public key records must hold a plain value".to_string())
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The `/cas/<multihash>` key a value is stored under by `PUT_CAS`, with the
+/// multihash in hex.
+pub fn content_key(value: &[u8]) -> Key {
+    multihash_key(&sha256_multihash(value).to_bytes())
+}
+
+/// The SHA-256 multihash of `data`.
+pub fn sha256_multihash(data: &[u8]) -> Multihash<64> {
+    Multihash::wrap(SHA2_256, &Sha256::digest(data)).expect("a SHA-256 digest fits a multihash")
+}
+
+/// The `/cas/` key for an encoded multihash.
+pub fn multihash_key(hash: &[u8]) -> Key {
+    Key::new(&format!("/cas/{}", hex::encode(hash)))
+}
+
+/// Validates `/cas/<multihash>` records, whose value must hash to the key.
+/// Anyone may publish them, since the hash alone proves the value, but they
+/// cannot be deleted or changed.
+pub struct ContentValidator;
+
+impl RecordValidator for ContentValidator {
+    fn validate(&self, key: &Key, record: &Envelope) -> Result<(), String> {
+        let hash = key
+            .as_ref()
+            .strip_prefix(b"/cas/")
+            .and_then(|hash| hex::decode(hash).ok())
+            .and_then(|hash| Multihash::<64>::from_bytes(&hash).ok())
+            .ok_or("key is not /cas/<multihash>")?;
+        // Identity "hashes" would let anyone pick the value.
+        let digest: fn(&[u8]) -> Vec<u8> = match hash.code() {
+            SHA2_256 => |data| Sha256::digest(data).to_vec(),
+            SHA2_512 => |data| Sha512::digest(data).to_vec(),
+            code => return Err(format!("unsupported hash function {:#x}", code)),
+        };
+        let matches = match record.kind {
+            Kind::Value => digest(&record.payload) == hash.digest(),
+            // A chunked value, whose manifest carries the SHA-256 of the
+            // whole value. The chunks are checked when they are fetched.
+            Kind::Manifest => {
+                let manifest = Manifest::decode(&record.payload).map_err(|e| e.to_string())?;
+                hash.code() == SHA2_256 && manifest.hash == hash.digest()
+            }
+            _ => {
+                return Err("content-addressed records cannot be deleted or merged".to_string())
+            }
+        };
+        if !matches {
+            return Err("value does not match its hash".to_string());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn envelope(kind: Kind, payload: &[u8]) -> Envelope {
+        Envelope {
+            kind,
+            publisher: PeerId::random(),
+            seq: None,
+            created_at: 0,
+            expires_at: None,
+            payload: payload.to_vec(),
+        }
+    }
+
+    fn validate(key: &Key, kind: Kind, payload: &[u8]) -> Result<(), String> {
+        ContentValidator.validate(key, &envelope(kind, payload))
+    }
+
+    #[test]
+    fn accepts_values_matching_their_key() {
+        assert_eq!(validate(&content_key(b"value"), Kind::Value, b"value"), Ok(()));
+
+        let sha512 = Multihash::<64>::wrap(SHA2_512, &Sha512::digest(b"value")).unwrap();
+        let key = multihash_key(&sha512.to_bytes());
+        assert_eq!(validate(&key, Kind::Value, b"value"), Ok(()));
+    }
+
+    #[test]
+    fn rejects_values_not_matching_their_key() {
+        assert_eq!(
+            validate(&content_key(b"value"), Kind::Value, b"other"),
+            Err("value does not match its hash".to_string())
+        );
+    }
+
+    #[test]
+    fn rejects_identity_hashes() {
+        let identity = Multihash::<64>::wrap(0x00, b"value").unwrap();
+        let key = multihash_key(&identity.to_bytes());
+        assert_eq!(
+            validate(&key, Kind::Value, b"value"),
+            Err("unsupported hash function 0x0".to_string())
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_keys() {
+        for key in ["/cas/", "/cas/zz", "/cas/1220", "/other/1220"] {
+            assert!(validate(&Key::new(&key), Kind::Value, b"value").is_err());
+        }
+    }
+
+    #[test]
+    fn accepts_manifests_of_the_value_hashed_into_the_key() {
+        let value = vec![7; 2 * crate::chunk::CHUNK_SIZE];
+        let (manifest, _) = Manifest::split(&value).unwrap();
+        let payload = manifest.encode();
+        assert_eq!(validate(&content_key(&value), Kind::Manifest, &payload), Ok(()));
+        assert_eq!(
+            validate(&content_key(b"other"), Kind::Manifest, &payload),
+            Err("value does not match its hash".to_string())
+        );
+
+        // The manifest only carries a SHA-256, so other hash functions
+        // cannot be checked against it.
+        let sha512 = Multihash::<64>::wrap(SHA2_512, &Sha512::digest(&value)).unwrap();
+        let key = multihash_key(&sha512.to_bytes());
+        assert!(validate(&key, Kind::Manifest, &payload).is_err());
+    }
+
+    #[test]
+    fn rejects_tombstones_and_crdts() {
+        let key = content_key(b"value");
+        for kind in [Kind::Tombstone, Kind::Crdt] {
+            assert_eq!(
+                validate(&key, kind, b"value"),
+                Err("content-addressed records cannot be deleted or merged".to_string())
+            );
+        }
+    }
+}
@@ -0,0 +1,225 @@
+//! Append-only write-ahead log.
+//!
+//! Each entry is framed as `len: u32 | crc32(payload): u32 | payload`. An
+//! entry is only considered written once it has been synced, so on replay a
+//! short or mismatching frame can only be a write torn by a crash; it and
+//! everything after it are truncated away.
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+const HEADER_LEN: usize = 8;
+
+pub struct Wal {
+    file: File,
+    entries: usize,
+    /// End of the last intact entry, where the next one is written.
+    offset: u64,
+}
+
+impl Wal {
+    /// Opens the log at `path`, returning it together with the payloads of
+    /// all intact entries in the order they were appended.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<(Self, Vec<Vec<u8>>)> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+
+        let mut data = Vec::new();
+        file.read_to_end(&mut data)?;
+
+        let mut payloads = Vec::new();
+        let mut offset = 0;
+        while offset < data.len() {
+            match parse_entry(&data[offset..]) {
+                Some(payload) => {
+                    payloads.push(payload.to_vec());
+                    offset += HEADER_LEN + payload.len();
+                }
+                None => {
+                    eprintln!(
+                        "Truncating torn write-ahead log entry at offset {} ({} bytes dropped)",
+                        offset,
+                        data.len() - offset
+                    );
+                    file.set_len(offset as u64)?;
+                    file.sync_all()?;
+                    break;
+                }
+            }
+        }
+        file.seek(SeekFrom::Start(offset as u64))?;
+
+        let wal = Wal {
+            file,
+            entries: payloads.len(),
+            offset: offset as u64,
+        };
+        Ok((wal, payloads))
+    }
+
+    /// Appends an entry and syncs it to disk before returning. On failure
+    /// the log is cut back to its last intact entry, so a partly written
+    /// frame cannot hide the entries appended after it from replay.
+    pub fn append(&mut self, payload: &[u8]) -> io::Result<()> {
+        let mut entry = Vec::with_capacity(HEADER_LEN + payload.len());
+        entry.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        entry.extend_from_slice(&crc32fast::hash(payload).to_be_bytes());
+        entry.extend_from_slice(payload);
+
+        let written = self
+            .file
+            .write_all(&entry)
+            .and_then(|()| self.file.sync_data());
+        if let Err(err) = written {
+            self.file.set_len(self.offset)?;
+            self.file.seek(SeekFrom::Start(self.offset))?;
+            return Err(err);
+        }
+        self.offset += entry.len() as u64;
+        self.entries += 1;
+        Ok(())
+    }
+
+    /// Discards every entry, e.g. once they are covered by a snapshot.
+    pub fn reset(&mut self) -> io::Result<()> {
+        self.file.set_len(0)?;
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.sync_all()?;
+        self.entries = 0;
+        self.offset = 0;
+        Ok(())
+    }
+
+    /// Number of entries in the log.
+    pub fn len(&self) -> usize {
+        self.entries
+    }
+}
+
+fn parse_entry(data: &[u8]) -> Option<&[u8]> {
+    if data.len() < HEADER_LEN {
+        return None;
+    }
+    let mut len = [0; 4];
+    len.copy_from_slice(&data[0..4]);
+    let mut crc = [0; 4];
+    crc.copy_from_slice(&data[4..8]);
+
+    let payload = data[HEADER_LEN..].get(..u32::from_be_bytes(len) as usize)?;
+    if crc32fast::hash(payload) != u32::from_be_bytes(crc) {
+        return None;
+    }
+    Some(payload)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{fs, path::PathBuf};
+
+    /// A fresh log path under the system temp directory.
+    fn temp_path(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("libp2pdb-wal-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir.join("test.wal")
+    }
+
+    fn write_entries(path: &Path, payloads: &[&[u8]]) {
+        let (mut wal, entries) = Wal::open(path).unwrap();
+        assert!(entries.is_empty());
+        for payload in payloads {
+            wal.append(payload).unwrap();
+        }
+        assert_eq!(wal.len(), payloads.len());
+    }
+
+    /// Appends one entry to an existing log.
+    fn append_entry(path: &Path, payload: &[u8]) {
+        let (mut wal, _) = Wal::open(path).unwrap();
+        wal.append(payload).unwrap();
+    }
+
+    #[test]
+    fn replays_appended_entries() {
+        let path = temp_path("replay");
+        write_entries(&path, &[b"one", b"two", b""]);
+
+        let (wal, entries) = Wal::open(&path).unwrap();
+        assert_eq!(entries, vec![b"one".to_vec(), b"two".to_vec(), Vec::new()]);
+        assert_eq!(wal.len(), 3);
+    }
+
+    #[test]
+    fn truncates_torn_tail() {
+        let path = temp_path("torn");
+        write_entries(&path, &[b"one", b"two"]);
+        let intact = fs::metadata(&path).unwrap().len();
+        append_entry(&path, b"three");
+        // Cut the last entry short, as a crash mid-write would.
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len(intact + HEADER_LEN as u64 + 2).unwrap();
+
+        let (_, entries) = Wal::open(&path).unwrap();
+        assert_eq!(entries, vec![b"one".to_vec(), b"two".to_vec()]);
+        assert_eq!(fs::metadata(&path).unwrap().len(), intact);
+    }
+
+    #[test]
+    fn truncates_at_bad_crc() {
+        let path = temp_path("crc");
+        write_entries(&path, &[b"one", b"two", b"three"]);
+        let mut data = fs::read(&path).unwrap();
+        // Flip a payload byte of the second entry.
+        let second = HEADER_LEN + 3;
+        data[second + HEADER_LEN] ^= 0xff;
+        fs::write(&path, &data).unwrap();
+
+        let (wal, entries) = Wal::open(&path).unwrap();
+        assert_eq!(entries, vec![b"one".to_vec()]);
+        assert_eq!(wal.len(), 1);
+        assert_eq!(fs::metadata(&path).unwrap().len(), second as u64);
+    }
+
+    #[test]
+    fn appends_after_recovery() {
+        let path = temp_path("recover");
+        write_entries(&path, &[b"one", b"two"]);
+        let mut data = fs::read(&path).unwrap();
+        data.extend_from_slice(&[0, 0, 0, 9, 1, 2]);
+        fs::write(&path, &data).unwrap();
+
+        let (mut wal, entries) = Wal::open(&path).unwrap();
+        assert_eq!(entries.len(), 2);
+        wal.append(b"three").unwrap();
+        drop(wal);
+
+        let (wal, entries) = Wal::open(&path).unwrap();
+        assert_eq!(
+            entries,
+            vec![b"one".to_vec(), b"two".to_vec(), b"three".to_vec()]
+        );
+        assert_eq!(wal.len(), 3);
+    }
+
+    #[test]
+    fn reset_discards_entries() {
+        let path = temp_path("reset");
+        write_entries(&path, &[b"one"]);
+        let (mut wal, _) = Wal::open(&path).unwrap();
+        wal.reset().unwrap();
+        wal.append(b"two").unwrap();
+        drop(wal);
+
+        let (_, entries) = Wal::open(&path).unwrap();
+        assert_eq!(entries, vec![b"two".to_vec()]);
+    }
+}
@@ -0,0 +1,62 @@
+//! Change notifications for watched keys.
+//!
+//! Every node subscribes to one gossipsub topic for the whole namespace.
+//! When this node writes or deletes a key it publishes the key and the
+//! signed record on it, and each node only shows the changes to the keys it
+//! `WATCH`es. Sharing the topic keeps every node in the gossipsub mesh,
+//! whose connections stay open, so a change reaches watchers the writer is
+//! not connected to itself. Since gossipsub messages are signed by their
+//! source, and the record by its publisher, a notification is only shown if
+//! both are the same peer.
+//!
+//! ```text
+//! key | record
+//! ```
+
+use crate::codec::{invalid_data, Reader, Writer};
+use libp2p::{gossipsub::IdentTopic, kad::RecordKey as Key};
+use std::io;
+
+const TOPIC: &str = "/libp2pdb/watch";
+
+/// The topic carrying changes to all keys.
+pub fn topic() -> IdentTopic {
+    IdentTopic::new(TOPIC)
+}
+
+/// Encodes a change to `key`, whose signed record is now `value`.
+pub fn encode(key: &Key, value: &[u8]) -> Vec<u8> {
+    let mut w = Writer::new();
+    w.bytes(key.as_ref()).bytes(value);
+    w.into_inner()
+}
+
+/// Decodes a change into the key and its signed record.
+pub fn decode(data: &[u8]) -> io::Result<(Key, Vec<u8>)> {
+    let mut r = Reader::new(data);
+    let key = Key::from(r.bytes()?.to_vec());
+    let value = r.bytes()?.to_vec();
+    if !r.is_empty() {
+        return Err(invalid_data("trailing bytes after change"));
+    }
+    Ok((key, value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips() {
+        let key = Key::new(b"key");
+        let data = encode(&key, b"record");
+        assert_eq!(decode(&data).unwrap(), (key, b"record".to_vec()));
+    }
+
+    #[test]
+    fn rejects_trailing_bytes() {
+        let mut data = encode(&Key::new(b"key"), b"record");
+        data.push(0);
+        assert!(decode(&data).is_err());
+    }
+}